@@ -70,6 +70,29 @@ impl SysFont {
         }
     }
 
+    // font as 8 bytes per character indexed by ascii code, most significant bit is the left pixel.
+    // codes 128-255 keep the glyph of the same screen code
+    pub fn ascii_rom(&self) -> Vec<u8> {
+        let mut rom = Vec::<u8>::with_capacity(256 * 8);
+        for code in 0..256 {
+            let charcode = match code {
+                0x20..=0x7E => self.ascii_to_petscii(code as u8 as char),
+                0x00..=0x7F => 32,
+                _ => code as u8,
+            };
+            let data_x = 8 * (charcode % 32) as usize;
+            let data_y = 8 * (charcode / 32) as usize;
+            for i in data_y..(data_y + 8) {
+                let mut line = 0u8;
+                for j in data_x..(data_x + 8) {
+                    line = (line << 1) | self.data[j + i * 256];
+                }
+                rom.push(line);
+            }
+        }
+        rom
+    }
+
     fn ascii_to_petscii(&self, c_ascii: char) -> u8 {
         match c_ascii {
             '@' => 0,
@@ -1,7 +1,7 @@
 // memory debug window
 extern crate minifb;
 
-mod font;
+pub mod font;
 
 use crate::virpc;
 use minifb::*;
@@ -40,12 +40,12 @@ impl Debugger {
     }
 
 
-    pub fn render(&mut self, cpu: &mut virpc::cpu::CPUShared, memory: &mut virpc::memory::MemShared) {
+    pub fn render(&mut self, memory: &mut virpc::memory::MemShared, pc: u32, p: u8) {
         if self.debug_window.is_open() {
             self.draw_border();
 
             self.draw_ram(memory);
-            self.draw_cpu(cpu);
+            self.draw_cpu(pc, p);
 
             let _ = self.debug_window.update_with_buffer(&self.window_buffer, DEBUG_W, DEBUG_H);
         }
@@ -113,11 +113,11 @@ impl Debugger {
 
 
     // draw CPU flags and registers
-    fn draw_cpu(&mut self, cpu: &mut virpc::cpu::CPUShared) {
+    fn draw_cpu(&mut self, pc: u32, p: u8) {
         let mut pc_txt = Vec::new();
         let mut p_txt = Vec::new();
-        let _ = write!(&mut pc_txt, "${:04X}", cpu.borrow_mut().get_pc());
-        let _ = write!(&mut p_txt, "[{:08b}]", cpu.borrow_mut().p);
+        let _ = write!(&mut pc_txt, "${:04X}", pc);
+        let _ = write!(&mut p_txt, "[{:08b}]", p);
         
         self.font.draw_text(&mut self.window_buffer, DEBUG_W, 44, 22, "PC:", 0x0F);
         self.font.draw_text(&mut self.window_buffer, DEBUG_W, 47, 22, &String::from_utf8(pc_txt).unwrap().to_owned()[..], 0x0E);
@@ -1,11 +1,36 @@
 use ncurses::*;
-use crate::virpc;
+use crate::virpc::breakpoints;
+use crate::virpc::coverage;
+use crate::virpc::machine;
+use crate::virpc::memory;
 use crate::virpc::cpu;
 
 static COLOR_PAIR_DEFAULT: i16 = 1;
 static COLOR_PAIR_KEYWORD: i16 = 2;
 static COLOR_PAIR_CURRENT: i16 = 3;
+static COLOR_PAIR_HOT: i16 = 4;  // profiled lines, at least a quarter of the most executed one
+static COLOR_PAIR_WARM: i16 = 5; // at least 1/32
+static COLOR_PAIR_COVERED: i16 = 6; // ran, without a profile
+static COLOR_PAIR_PARTIAL: i16 = 7; // conditional jump that went one way only
 static MEMORY_SIZE: u32 = 0x080000;
+static CONSOLE_LINES: usize = 500; // output kept for scrolling back
+static CONSOLE_COMMANDS: [&str; 16] = ["break", "delete", "step", "next", "finish", "until", "run", "continue", "stop", "x", "print", "set", "goto", "save", "load", "trace"];
+static CONSOLE_HELP: [&str; 14] = [
+    "break <adr> [if <condition>]   set or remove a breakpoint, like break loop if [reg0] == 5",
+    "delete <adr>                   remove a breakpoint",
+    "step [n]                       execute n instructions",
+    "next, finish                   step over a call, or run until the current call returns",
+    "until <adr>, run <n>           run to an address, or run n instructions",
+    "continue, stop                 run or pause the machine",
+    "x/<n><x|w> <adr>               show n bytes or words of memory, like x/32x 0xE000",
+    "print <expr>                   show a value, like print [reg0]",
+    "set <adr>=<expr>               write a word, or a byte with b[adr], like set [reg0]=5",
+    "goto <adr>                     show the code at an address",
+    "save [file], load [file]       snapshot of the machine, machine.snap by default",
+    "trace on [file], trace off     log every instruction, to trace.jsonl by default",
+    "addresses and values are numbers, labels, pc, stack or regN, with [] or b[] to read memory",
+    "<tab> completes commands and labels, <up>/<down> go through the history",
+];
 
 //TODO sound chip
 //TODO keyboard
@@ -72,12 +97,16 @@ pub struct Windows {
     mem_address : u32,
     mem_highlight : u32,
     mem_highlight_size : u32,
-    virpc : virpc::Virpc,
+    virpc : machine::Machine,
     run_program : bool,
+    screenshot_count : u32,
+    message : String, // shown on the top row instead of the keys, until the next key
+    console_history : Vec<String>,
+    console_output : Vec<String>,
 }
 
 impl Windows {
-    pub fn new(cpu : cpu::CPUShared, virpc : virpc::Virpc) -> Windows {
+    pub fn new(cpu : cpu::CPUShared, virpc : machine::Machine) -> Windows {
 
         let mut win = Windows {
             menu1 : 0 as MENU,
@@ -114,6 +143,10 @@ impl Windows {
             mem_highlight_size : 0,
             virpc : virpc,
             run_program : false,
+            screenshot_count : 0,
+            message : String::new(),
+            console_history : Vec::new(),
+            console_output : Vec::new(),
         };
 
         initscr();
@@ -124,6 +157,10 @@ impl Windows {
         init_pair(COLOR_PAIR_DEFAULT, COLOR_WHITE, COLOR_BLACK);
         init_pair(COLOR_PAIR_KEYWORD, COLOR_BLACK, COLOR_WHITE);
         init_pair(COLOR_PAIR_CURRENT, COLOR_WHITE, COLOR_GREEN);
+        init_pair(COLOR_PAIR_HOT, COLOR_WHITE, COLOR_RED);
+        init_pair(COLOR_PAIR_WARM, COLOR_BLACK, COLOR_YELLOW);
+        init_pair(COLOR_PAIR_COVERED, COLOR_BLACK, COLOR_CYAN);
+        init_pair(COLOR_PAIR_PARTIAL, COLOR_BLACK, COLOR_MAGENTA);
 
         refresh();//needed for screen size
         getmaxyx(stdscr(), &mut win.screen_height, &mut win.screen_width);
@@ -167,8 +204,57 @@ impl Windows {
         endwin();
     }
 
+    // exchange memory and commands with the machine thread
     pub fn run_virpc(&mut self) {
-        self.virpc.run();
+        self.virpc.set_labels(&self.cpu_reader.borrow().labels);
+        let synced = self.virpc.sync();
+        self.run_program = self.virpc.status();//a breakpoint or watchpoint may have stopped it
+        if synced && !self.run_program {
+            //show where a step or breakpoint stopped
+            let pc = self.cpu_reader.borrow_mut().read_int_le(0xF000);
+            if pc != self.current_pc {
+                //the heat of the lines is from a fresh profile
+                if self.virpc.is_profiling() {
+                    self.virpc.fetch_profile();
+                }
+                self.refresh_fast();
+                self.refresh_code();
+            }
+        }
+    }
+
+    // shown on the top row until the next key
+    pub fn show_message(&mut self, message : &str) {
+        self.message = message.to_string();
+        self.refresh_fast();
+    }
+
+    // save the machine with the labels, for load_snapshot
+    pub fn save_snapshot(&mut self, filename : &str) -> Result<(), String> {
+        let labels = self.cpu_reader.borrow().labels.clone();
+        self.virpc.save_snapshot(filename, labels)
+    }
+
+    // continue from a saved machine, with its labels
+    pub fn load_snapshot(&mut self, filename : &str) -> Result<(), String> {
+        let labels = self.virpc.load_snapshot(filename)?;
+        self.cpu_reader.borrow_mut().labels = labels;
+        self.run_program = false;
+        self.reset_edit();
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();
+        Ok(())
+    }
+
+    // the complete coverage report, annotated with the labels
+    pub fn save_coverage(&mut self, filename : &str) -> std::io::Result<()> {
+        self.virpc.stop_coverage();
+        let report = self.coverage_report();
+        std::fs::write(filename, report)
+    }
+
+    pub fn quit_virpc(&mut self, screenshot : &str) -> std::io::Result<()> {
+        self.virpc.quit(screenshot)
     }
 
     //////////////////////////////////////////////
@@ -289,12 +375,27 @@ impl Windows {
 
     pub fn refresh_fast(&mut self) {
         self.current_pc = self.cpu_reader.borrow_mut().read_int_le(0xF000);
-        let status = match self.virpc.status() {
+        let running = self.virpc.status();
+        let status = match running {
             true => "running",
             false => "stopped",
         };
-        let s = format!("edit:{:08X},current:{:08X} {} <F5 run/pause> <F6 reset> <F8 step> <F9 breakpoint>",self.edit_line,self.current_pc, status);
+        let recording = match self.virpc.is_recording() {
+            true => " rec",
+            false => "    ",
+        };
+        //after a watchpoint stopped the cpu, show who accessed it instead of the keys
+        let keys = match self.virpc.watch_hit() {
+            _ if !self.message.is_empty() => self.message.clone(),
+            Some(hit) if !running => {
+                let access = if hit.watch == memory::Watch::Read { "read" } else { "write" };
+                format!("watch: {} of ${:08X} at ${:08X}, {:08X} -> {:08X}", access, hit.address, hit.pc, hit.old, hit.new)
+            }
+            _ => "<F2 snapshot> <F3 run back> <F4 step back> <F5 run/pause> <F6 reset> <F7 record> <F8 step> <F9 breakpoint> <F10 step over> <F11 step out> <r run to> <n run n> <b breakpoints> <p profile> <v coverage> <: console> <F12 screenshot>".to_string(),
+        };
+        let s = format!("edit:{:08X},current:{:08X} {}{} {:>8} ips {}",self.edit_line,self.current_pc, status, recording, self.virpc.ips(), keys);
         mvprintw(0,0,s.as_str());
+        clrtoeol();
         refresh();   
     }
 
@@ -383,6 +484,7 @@ impl Windows {
 
         self.cpu_reader.borrow_mut().data.clear();
         self.cpu_reader.borrow_mut().data = cpu::CPU::get_variables_list();
+        let max_executions = self.virpc.profile().map_or(0, |p| p.max_executions());
 
         for i in 0..end {
             tpc = self.cpu_reader.borrow_mut().disassemble(lpc);
@@ -416,7 +518,13 @@ impl Windows {
                 if self.current_pc >= lpc && self.current_pc < tpc && self.virpc.status() == false {
                     wattrset(self.win2_sub, COLOR_PAIR(3));
                 }
-                wprintw(self.win2_sub, self.cpu_reader.borrow_mut().instruction_to_text().as_str());
+                else if i != self.edit_line {
+                    if let Some(pair) = self.heat(lpc, max_executions).or_else(|| self.covered(lpc)) {
+                        wattrset(self.win2_sub, COLOR_PAIR(pair));
+                    }
+                }
+                let line = self.code_line(lpc);
+                wprintw(self.win2_sub, line.as_str());
                 wattrset(self.win2_sub, COLOR_PAIR(1));
             }
             lpc = tpc;
@@ -942,7 +1050,8 @@ impl Windows {
                                 wattrset(self.win2_sub, COLOR_PAIR(2));
                                 self.edit_pc = lpc;
                             }
-                            wprintw(self.win2_sub, self.cpu_reader.borrow_mut().instruction_to_text().as_str());
+                            let line = self.code_line(lpc);
+                            wprintw(self.win2_sub, line.as_str());
                             wattrset(self.win2_sub, COLOR_PAIR(1));
                             lpc = tpc;
                         }
@@ -978,7 +1087,8 @@ impl Windows {
                                 wattrset(self.win2_sub, COLOR_PAIR(2));
                                 self.edit_pc = lpc;
                             }
-                            wprintw(self.win2_sub, self.cpu_reader.borrow_mut().instruction_to_text().as_str());
+                            let line = self.code_line(lpc);
+                            wprintw(self.win2_sub, line.as_str());
                             wattrset(self.win2_sub, COLOR_PAIR(1));
                             lpc = tpc;
                         }
@@ -1003,6 +1113,786 @@ impl Windows {
         self.resize_check();//show the edited value
     }
 
+    //list of breakpoints: enable/disable, edit the condition or delete the selected one
+    fn breakpoint_list(&mut self) {
+        let lwin_menu = Windows::create_win(" ",self.wd(1,'h') + self.wd(3,'h') + self.wd(4,'h'), self.wd(3,'w'), self.wd(1,'y'), self.wd(1,'x'));
+        let mut select = 0;
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            let list = self.virpc.breakpoints().list().to_vec();
+            werase(lwin_menu);
+            box_(lwin_menu,0,0);
+            mvwprintw(lwin_menu,0,1," breakpoints <e enable> <c condition> <d delete> <enter goto> ");
+            if list.is_empty() {
+                mvwprintw(lwin_menu,1,1,"none yet, F9 sets one on the edited line");
+                wrefresh(lwin_menu);
+                getch();
+                break;
+            }
+            if select >= list.len() {
+                select = list.len() - 1;
+            }
+
+            let mut items = Vec::new();
+            for b in list.iter() {
+                let tag = match self.cpu_reader.borrow_mut().get_label(b.address) {
+                    Some(lbl) => lbl.tag,
+                    None => "".to_string(),
+                };
+                let s = format!("{} ${:04X} {:12} hits:{:<6} {}", if b.enabled {'*'} else {'o'}, b.address, tag, b.hits, b.condition);
+                items.push(new_item(s.as_bytes(), " ".as_bytes()));
+            }
+            let menu = Windows::create_menu(&mut items,lwin_menu,select as u32);
+            unpost_menu(menu);
+            menu_opts_off(menu, O_SHOWDESC);
+            post_menu(menu);
+            wrefresh(lwin_menu);
+
+            ch = getch();
+            match ch {
+                KEY_UP => {
+                    menu_driver(menu, REQ_UP_ITEM);
+                }
+                KEY_DOWN => {
+                    menu_driver(menu, REQ_DOWN_ITEM);
+                }
+                _ => {}
+            }
+            select = item_index(current_item(menu)) as usize;
+            Windows::destroy_menu(menu,&mut items);
+
+            let adr = list[select].address;
+            match ch {
+                0x65 => {//e
+                    self.virpc.enable_breakpoint(adr, !list[select].enabled);
+                }
+                0x64 => {//d
+                    self.virpc.remove_breakpoint(adr);
+                }
+                0x63 => {//c
+                    let condition = list[select].condition.clone();
+                    self.input_condition(lwin_menu, adr, condition);
+                }
+                0xa => {//enter
+                    self.goto_code(adr);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        Windows::destroy_win(lwin_menu);
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();//show the changed markers
+    }
+
+    //functions by the time spent in them, with the callers and callees of the selected one
+    fn profile_report(&mut self) {
+        let h = self.wd(1,'h') + self.wd(3,'h') + self.wd(4,'h');
+        let lwin_menu = Windows::create_win(" ",h, self.wd(3,'w'), self.wd(1,'y'), self.wd(1,'x'));
+        let list_h = (h - 6).max(1);
+        let mut select = 0;
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            self.virpc.fetch_profile();
+            werase(lwin_menu);
+            box_(lwin_menu,0,0);
+            mvwprintw(lwin_menu,0,1," profile <s start/stop> <c clear> <x export> <enter goto> ");
+
+            let profile = self.virpc.profile().cloned().unwrap_or_default();
+            let functions = profile.functions();
+            let total = profile.total().cycles.max(1);
+            let state = if self.virpc.is_profiling() { "profiling" } else { "stopped" };
+            mvwprintw(lwin_menu,h-1,1,format!(" {}, {} cycles ",state,profile.total().cycles).as_str());
+            if functions.is_empty() {
+                mvwprintw(lwin_menu,1,1,"no profile yet, s starts one");
+                wrefresh(lwin_menu);
+                ch = getch();
+                match ch {
+                    0x73 => {//s
+                        let profiling = self.virpc.is_profiling();
+                        self.virpc.set_profiling(!profiling);
+                    }
+                    0x63 => self.virpc.clear_profile(),//c
+                    _ => break,
+                }
+                continue;
+            }
+            if select >= functions.len() {
+                select = functions.len() - 1;
+            }
+
+            let mut items = Vec::new();
+            for f in functions.iter() {
+                let name = self.cpu_reader.borrow_mut().get_mem_label(f.address);
+                let s = format!("{:5.1}% {:5.1}% calls:{:<8} {}",
+                    f.own.cycles as f64 * 100.0 / total as f64, f.total.cycles as f64 * 100.0 / total as f64, f.calls, name);
+                items.push(new_item(s.as_bytes(), " ".as_bytes()));
+            }
+            let menu = Windows::create_menu(&mut items,lwin_menu,select as u32);
+            unpost_menu(menu);
+            set_menu_format(menu, list_h, 1);
+            menu_opts_off(menu, O_SHOWDESC);
+            post_menu(menu);
+
+            // the call graph around the selected function
+            let selected = functions[select].address;
+            let callers: Vec<String> = profile.callers(selected).iter()
+                .map(|&(adr, calls)| format!("{}({})", self.cpu_reader.borrow_mut().get_mem_label(adr), calls)).collect();
+            let callees: Vec<String> = profile.callees(selected).iter()
+                .map(|&(adr, calls)| format!("{}({})", self.cpu_reader.borrow_mut().get_mem_label(adr), calls)).collect();
+            let w = (self.wd(3,'w') - 2) as usize;
+            let mut line = format!("callers: {}", callers.join(" "));
+            line.truncate(w);
+            mvwprintw(lwin_menu,h-4,1,line.as_str());
+            let mut line = format!("callees: {}", callees.join(" "));
+            line.truncate(w);
+            mvwprintw(lwin_menu,h-3,1,line.as_str());
+            mvwprintw(lwin_menu,h-2,1,"own% total%");
+            wrefresh(lwin_menu);
+
+            ch = getch();
+            match ch {
+                KEY_UP => {
+                    menu_driver(menu, REQ_UP_ITEM);
+                }
+                KEY_DOWN => {
+                    menu_driver(menu, REQ_DOWN_ITEM);
+                }
+                _ => {}
+            }
+            select = item_index(current_item(menu)) as usize;
+            Windows::destroy_menu(menu,&mut items);
+
+            match ch {
+                0x73 => {//s
+                    let profiling = self.virpc.is_profiling();
+                    self.virpc.set_profiling(!profiling);
+                }
+                0x63 => {//c
+                    self.virpc.clear_profile();
+                }
+                0x78 => {//x
+                    let folded = {
+                        let mut cpu = self.cpu_reader.borrow_mut();
+                        profile.folded(|adr| cpu.get_mem_label(adr))
+                    };
+                    self.message = match std::fs::write("profile.folded", folded) {
+                        Ok(_) => "saved profile.folded".to_string(),
+                        Err(why) => format!("profile.folded: {}", why),
+                    };
+                    break;
+                }
+                0xa => {//enter
+                    self.goto_code(functions[select].address);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        Windows::destroy_win(lwin_menu);
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();
+        self.refresh_fast();
+    }
+
+    //colour of a profiled line, by how often it ran compared to the most executed one
+    fn heat(&self, adr : u32, max : u64) -> Option<i16> {
+        let executions = self.virpc.profile()?.hits(adr).executions;
+        if executions == 0 {
+            None
+        } else if executions * 4 >= max {
+            Some(COLOR_PAIR_HOT)
+        } else if executions * 32 >= max {
+            Some(COLOR_PAIR_WARM)
+        } else {
+            None
+        }
+    }
+
+    //colour of a line that ran while covering, when there is no profile to show
+    fn covered(&self, adr : u32) -> Option<i16> {
+        let coverage = self.virpc.coverage()?;
+        match coverage.branch(adr) {
+            Some(branch) if !branch.is_covered() => Some(COLOR_PAIR_PARTIAL),
+            _ if coverage.executions(adr) > 0 => Some(COLOR_PAIR_COVERED),
+            _ => None,
+        }
+    }
+
+    fn coverage_report(&mut self) -> String {
+        let labels = self.cpu_reader.borrow().labels.clone();
+        match self.virpc.coverage() {
+            Some(coverage) => coverage.report(&self.virpc.memory.borrow(), &labels),
+            None => coverage::Coverage::new().report(&self.virpc.memory.borrow(), &labels),
+        }
+    }
+
+    //start, stop or clear coverage, and save the report
+    fn coverage_dialog(&mut self) {
+        let lwin_menu = Windows::create_win(" ",6, self.wd(3,'w'), self.wd(3,'y'), self.wd(3,'x'));
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            self.virpc.sync();
+            werase(lwin_menu);
+            box_(lwin_menu,0,0);
+            mvwprintw(lwin_menu,0,1," coverage <s start/stop> <c clear> <x export> ");
+            let report = self.coverage_report();
+            let state = if self.virpc.is_covering() { "covering" } else { "stopped" };
+            mvwprintw(lwin_menu,1,1,state);
+            for (i, line) in report.lines().take(2).enumerate() {
+                mvwprintw(lwin_menu,2 + i as i32,1,line);
+            }
+            wrefresh(lwin_menu);
+
+            ch = getch();
+            match ch {
+                0x73 => {//s
+                    let covering = self.virpc.is_covering();
+                    self.virpc.set_coverage(!covering);
+                }
+                0x63 => {//c
+                    self.virpc.clear_coverage();
+                }
+                0x78 => {//x
+                    self.message = match std::fs::write("coverage.txt", report) {
+                        Ok(_) => "saved coverage.txt".to_string(),
+                        Err(why) => format!("coverage.txt: {}", why),
+                    };
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        Windows::destroy_win(lwin_menu);
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();
+        self.refresh_fast();
+    }
+
+    //edit the condition of a breakpoint on the bottom lines of the list window
+    fn input_condition(&mut self, lwin_menu : WINDOW, adr : u32, condition : String) {
+        let mut h = 0;
+        let mut w = 0;
+        getmaxyx(lwin_menu, &mut h, &mut w);
+        let blank = " ".repeat((w-2) as usize);
+        let mut val = condition;
+        let mut message = "like [reg0] == 5, empty always stops".to_string();
+        curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            mvwprintw(lwin_menu,h-3,1,blank.as_str());
+            mvwprintw(lwin_menu,h-3,1,message.as_str());
+            mvwprintw(lwin_menu,h-2,1,blank.as_str());
+            mvwprintw(lwin_menu,h-2,1,format!("condition: {}",val).as_str());
+            wrefresh(lwin_menu);
+            ch = getch();
+            match ch {
+                0xa => {//enter
+                    match self.virpc.set_breakpoint_condition(adr, val.as_str()) {
+                        Ok(()) => break,
+                        Err(why) => message = why,
+                    }
+                }
+                0x107 => {//backspace
+                    val.pop();
+                }
+                _ => {
+                    if (0x20..0x7f).contains(&ch) && (val.len() as i32) < w - 14 {
+                        val.push((ch as u8) as char);
+                    }
+                }
+            }
+        }
+        curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+    }
+
+    //ask for the number of instructions to run, None when cancelled
+    fn input_count(&mut self) -> Option<u64> {
+        let lwin_menu = Windows::create_win(" run n instructions ",3, self.wd(3,'w'), self.wd(3,'y'), self.wd(3,'x'));
+        let blank = " ".repeat((self.wd(3,'w')-2) as usize);
+        let mut val = String::new();
+        let mut result = None;
+        curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            mvwprintw(lwin_menu,1,1,blank.as_str());
+            mvwprintw(lwin_menu,1,1,format!("instructions: {}",val).as_str());
+            wrefresh(lwin_menu);
+            ch = getch();
+            match ch {
+                0xa => {//enter
+                    //0 would run on without stopping
+                    result = val.parse::<u64>().ok().filter(|&n| n > 0);
+                    break;
+                }
+                0x107 => {//backspace
+                    val.pop();
+                }
+                _ => {
+                    if (0x30..0x3a).contains(&ch) && val.len() < 12 {
+                        val.push((ch as u8) as char);
+                    }
+                }
+            }
+        }
+        curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+        Windows::destroy_win(lwin_menu);
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();
+        result
+    }
+
+    //save the machine to a file, or continue from one
+    fn snapshot_dialog(&mut self) {
+        let lwin_menu = Windows::create_win(" ",8, self.wd(3,'w'), self.wd(3,'y'), self.wd(3,'x'));
+        mvwprintw(lwin_menu,0,1," snapshot ");
+        let mut items = vec![
+            new_item("save", "the machine to the file"),
+            new_item("load", "continue from the file"),
+        ];
+        let menu = Windows::create_menu(&mut items,lwin_menu,0);
+        curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
+
+        let blank = " ".repeat((self.wd(3,'w')-2) as usize);
+        let mut filename = "machine.snap".to_string();
+        let mut result = None;
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            mvwprintw(lwin_menu,5,1,blank.as_str());
+            mvwprintw(lwin_menu,5,1,format!("file: {}",filename).as_str());
+            wrefresh(lwin_menu);
+            ch = getch();
+            match ch {
+                KEY_UP => {
+                    menu_driver(menu, REQ_UP_ITEM);
+                }
+                KEY_DOWN => {
+                    menu_driver(menu, REQ_DOWN_ITEM);
+                }
+                0xa => {//enter
+                    result = Some(item_index(current_item(menu)));
+                    break;
+                }
+                0x107 => {//backspace
+                    filename.pop();
+                }
+                _ => {
+                    if (0x21..0x7f).contains(&ch) && (filename.len() as i32) < self.wd(3,'w') - 10 {
+                        filename.push((ch as u8) as char);
+                    }
+                }
+            }
+        }
+        curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+        Windows::destroy_menu(menu,&mut items);
+        Windows::destroy_win(lwin_menu);
+
+        let done = match result {
+            Some(0) => self.save_snapshot(&filename).map(|_| format!("saved {}", filename)),
+            Some(_) => self.load_snapshot(&filename).map(|_| format!("loaded {}", filename)),
+            None => Ok(String::new()),
+        };
+        self.message = match done {
+            Ok(message) => message,
+            Err(why) => format!("{}: {}", filename, why),
+        };
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();
+        self.refresh_fast();
+    }
+
+    //show the last write to an address that is still in the journal on the top row
+    fn show_last_write(&mut self, adr : u32) {
+        self.message = match self.virpc.who_wrote(adr) {
+            Some(w) => format!("${:08X} last written at ${:08X} in cycle {}, {:02X} -> {:02X}", adr, w.pc, w.cycle, w.old, w.new),
+            None => format!("${:08X} wasn't written since the journal started", adr),
+        };
+        self.refresh_fast();
+    }
+
+    //pick how to watch a range of memory: stop on a read, a write or a change of its value
+    fn watch_dialog(&mut self, adr : u32, size : u32) {
+        let size = size.max(1);
+        let lwin_menu = Windows::create_win(" ",8, self.wd(3,'w'), self.wd(3,'y'), self.wd(3,'x'));
+        let current = self.virpc.watchpoints().iter().find(|w| w.address == adr).map(|w| w.hits);
+        let title = match current {
+            Some(hits) => format!(" watch ${:08X}, {} bytes, {} hits ", adr, size, hits),
+            None => format!(" watch ${:08X}, {} bytes ", adr, size),
+        };
+        mvwprintw(lwin_menu,0,1,title.as_str());
+
+        let mut items = vec![
+            new_item("read", "stop when it is read"),
+            new_item("write", "stop when it is written"),
+            new_item("change", "stop when a write changes it"),
+            new_item("remove", "stop watching it"),
+        ];
+        let menu = Windows::create_menu(&mut items,lwin_menu,0);
+        wrefresh(lwin_menu);
+
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            ch = getch();
+            match ch {
+                KEY_UP => {
+                    menu_driver(menu, REQ_UP_ITEM);
+                }
+                KEY_DOWN => {
+                    menu_driver(menu, REQ_DOWN_ITEM);
+                }
+                0xa => {//enter
+                    match item_index(current_item(menu)) {
+                        0 => self.virpc.watch(adr, size, memory::Watch::Read),
+                        1 => self.virpc.watch(adr, size, memory::Watch::Write),
+                        2 => self.virpc.watch(adr, size, memory::Watch::Change),
+                        _ => self.virpc.unwatch(adr),
+                    }
+                    break;
+                }
+                _ => {}
+            }
+            wrefresh(lwin_menu);
+        }
+
+        Windows::destroy_menu(menu,&mut items);
+        Windows::destroy_win(lwin_menu);
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();
+    }
+
+    //typed debugger commands on the bottom half of the screen, with history and completion of labels
+    fn console(&mut self) {
+        let h = (self.screen_height / 2).max(8);
+        let w = self.screen_width;
+        let lwin = Windows::create_win(" console <tab> complete <up/down> history <esc> close ", h, w, self.screen_height - h, 0);
+        let blank = " ".repeat((w-2).max(0) as usize);
+        let rows = (h - 3) as usize;
+        let mut line = String::new();
+        let mut recall = self.console_history.len(); //history entry on the line, len for a new one
+        if self.console_output.is_empty() {
+            self.console_print("type help for the commands".to_string());
+        }
+        curs_set(CURSOR_VISIBILITY::CURSOR_VISIBLE);
+        let mut ch = 0;
+        while ch != 27 { // ESC pressed, so quit
+            //commands can redraw the screen below the console
+            touchwin(lwin);
+            let first = self.console_output.len().saturating_sub(rows);
+            for row in 0..rows {
+                mvwprintw(lwin,1+row as i32,1,blank.as_str());
+                if let Some(text) = self.console_output.get(first + row) {
+                    let text : String = text.chars().take((w-2).max(0) as usize).collect();
+                    mvwprintw(lwin,1+row as i32,1,text.as_str());
+                }
+            }
+            mvwprintw(lwin,h-2,1,blank.as_str());
+            mvwprintw(lwin,h-2,1,format!("> {}",line).as_str());
+            wrefresh(lwin);
+            ch = getch();
+            match ch {
+                KEY_UP => {
+                    if recall > 0 {
+                        recall -= 1;
+                        line = self.console_history[recall].clone();
+                    }
+                }
+                KEY_DOWN => {
+                    if recall < self.console_history.len() {
+                        recall += 1;
+                        line = self.console_history.get(recall).cloned().unwrap_or_default();
+                    }
+                }
+                0x09 => {//tab
+                    self.complete(&mut line);
+                }
+                0xa => {//enter
+                    let command = line.trim().to_string();
+                    line.clear();
+                    if !command.is_empty() {
+                        if self.console_history.last() != Some(&command) {
+                            self.console_history.push(command.clone());
+                        }
+                        recall = self.console_history.len();
+                        self.console_print(format!("> {}", command));
+                        match self.run_command(&command) {
+                            Ok(lines) => {
+                                for text in lines {
+                                    self.console_print(text);
+                                }
+                            }
+                            Err(why) => self.console_print(format!("error: {}", why)),
+                        }
+                    }
+                }
+                0x107 => {//backspace
+                    line.pop();
+                }
+                _ => {
+                    if (0x20..0x7f).contains(&ch) && (line.len() as i32) < w - 6 {
+                        line.push((ch as u8) as char);
+                    }
+                }
+            }
+        }
+        curs_set(CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+        Windows::destroy_win(lwin);
+        self.screen_height = 0;//trigger an refresh_screen
+        self.resize_check();
+        self.refresh_fast();
+    }
+
+    fn console_print(&mut self, text : String) {
+        self.console_output.push(text);
+        if self.console_output.len() > CONSOLE_LINES {
+            self.console_output.remove(0);
+        }
+    }
+
+    //complete the last word with a command when it is the first, or else with a label
+    fn complete(&mut self, line : &mut String) {
+        let start = line.rfind(|c : char| !(c.is_alphanumeric() || c == '_')).map_or(0, |i| i + 1);
+        let prefix = line[start..].to_string();
+        let names : Vec<String> = match start {
+            0 => CONSOLE_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            _ => self.cpu_reader.borrow().labels.iter().map(|l| l.tag.clone()).collect(),
+        };
+        let found : Vec<&String> = names.iter().filter(|n| n.starts_with(&prefix)).collect();
+        if found.is_empty() {
+            return;
+        }
+        let mut common = found[0].clone();
+        for name in found.iter().skip(1) {
+            while !name.starts_with(&common) {
+                common.pop();
+            }
+        }
+        line.truncate(start);
+        line.push_str(&common);
+        if found.len() == 1 {
+            line.push(' ');
+        }
+        else {
+            let list : Vec<&str> = found.iter().map(|n| n.as_str()).collect();
+            self.console_print(list.join(" "));
+        }
+    }
+
+    //a value like the ones in breakpoint conditions, where labels stand for their address
+    fn console_value(&mut self, text : &str) -> Result<u32, String> {
+        if text.trim().is_empty() {
+            return Err("an address or value is missing".to_string());
+        }
+        let resolved = breakpoints::resolve_labels(text, &self.cpu_reader.borrow().labels);
+        breakpoints::evaluate(&resolved, &self.virpc.memory.borrow())
+    }
+
+    //run one console command, the lines are its output
+    fn run_command(&mut self, command : &str) -> Result<Vec<String>, String> {
+        let (word, rest) = match command.find(' ') {
+            Some(i) => (&command[..i], command[i+1..].trim()),
+            None => (command, ""),
+        };
+        match word {
+            "help" => Ok(CONSOLE_HELP.iter().map(|l| l.to_string()).collect()),
+            "break" | "b" => {
+                let (target, condition) = match rest.find(" if ") {
+                    Some(i) => (&rest[..i], rest[i+4..].trim()),
+                    None => (rest, ""),
+                };
+                let adr = self.console_value(target)?;
+                if condition.is_empty() {
+                    self.virpc.toggle_breakpoint(adr);
+                    let done = if self.virpc.breakpoints().get(adr).is_some() { "set" } else { "removed" };
+                    Ok(vec![format!("breakpoint at ${:08X} {}", adr, done)])
+                }
+                else {
+                    let resolved = breakpoints::resolve_labels(condition, &self.cpu_reader.borrow().labels);
+                    self.virpc.set_breakpoint_condition(adr, &resolved)?;
+                    Ok(vec![format!("breakpoint at ${:08X} if {}", adr, resolved)])
+                }
+            }
+            "delete" => {
+                let adr = self.console_value(rest)?;
+                self.virpc.remove_breakpoint(adr);
+                Ok(vec![format!("breakpoint at ${:08X} removed", adr)])
+            }
+            "step" | "s" => {
+                let count = match rest {
+                    "" => 1,
+                    _ => rest.parse::<u64>().ok().filter(|&n| n > 0).ok_or(format!("'{}' isn't a count", rest))?,
+                };
+                //a breakpoint or watchpoint can stop it before the count is done
+                self.virpc.run_instructions(count);
+                if !self.virpc.wait_stopped() {
+                    self.run_program = true;
+                    return Ok(vec![format!("stepping {}, still running", count)]);
+                }
+                self.run_program = false;
+                let pc = self.cpu_reader.borrow_mut().read_int_le(0xF000);
+                Ok(vec![format!("stepped {}, pc ${:08X}", count, pc)])
+            }
+            "next" | "n" => {
+                self.virpc.step_over();
+                self.run_program = true;
+                Ok(vec!["stepping over".to_string()])
+            }
+            "finish" => {
+                self.virpc.step_out()?;
+                self.run_program = true;
+                Ok(vec!["running until the call returns".to_string()])
+            }
+            "until" | "u" => {
+                let adr = self.console_value(rest)?;
+                self.virpc.run_to(adr);
+                self.run_program = true;
+                Ok(vec![format!("running to ${:08X}", adr)])
+            }
+            "run" => {
+                let count = rest.parse::<u64>().ok().filter(|&n| n > 0).ok_or(format!("'{}' isn't a count", rest))?;
+                self.virpc.run_instructions(count);
+                self.run_program = true;
+                Ok(vec![format!("running {} instructions", count)])
+            }
+            "continue" | "c" => {
+                self.virpc.continue_cpu();
+                self.run_program = true;
+                Ok(vec!["running".to_string()])
+            }
+            "stop" => {
+                self.virpc.stop();
+                self.virpc.wait();
+                self.run_program = false;
+                let pc = self.cpu_reader.borrow_mut().read_int_le(0xF000);
+                Ok(vec![format!("stopped, pc ${:08X}", pc)])
+            }
+            "print" | "p" => {
+                let val = self.console_value(rest)?;
+                Ok(vec![format!("{} = ${:08X} {}", rest, val, val)])
+            }
+            "set" => {
+                let i = rest.find('=').ok_or("set needs an =, like set [reg0]=5")?;
+                let (target, value) = (rest[..i].trim(), rest[i+1..].trim());
+                let val = self.console_value(value)?;
+                //[adr] and b[adr] like they are read in conditions, a bare address is a word too
+                if target.starts_with("b[") && target.ends_with(']') {
+                    let adr = self.console_value(&target[2..target.len()-1])?;
+                    self.cpu_reader.borrow_mut().write_byte(adr, val as u8);
+                    Ok(vec![format!("b[${:08X}] = ${:02X}", adr, val as u8)])
+                }
+                else {
+                    let adr = match target.starts_with('[') && target.ends_with(']') {
+                        true => self.console_value(&target[1..target.len()-1])?,
+                        false => self.console_value(target)?,
+                    };
+                    self.cpu_reader.borrow_mut().write_int_le(adr, val);
+                    Ok(vec![format!("[${:08X}] = ${:08X}", adr, val)])
+                }
+            }
+            "goto" | "g" => {
+                let adr = self.console_value(rest)?;
+                self.goto_code(adr);
+                self.focus = 0;
+                Ok(vec![format!("code at ${:08X}", adr)])
+            }
+            "save" | "load" => {
+                let filename = if rest.is_empty() { "machine.snap" } else { rest };
+                match word {
+                    "save" => self.save_snapshot(filename)?,
+                    _ => self.load_snapshot(filename)?,
+                }
+                Ok(vec![format!("{}d {}", word, filename)])
+            }
+            "trace" => {
+                let (state, file) = match rest.find(' ') {
+                    Some(i) => (&rest[..i], rest[i+1..].trim()),
+                    None => (rest, ""),
+                };
+                match state {
+                    "on" => {
+                        let filename = if file.is_empty() { "trace.jsonl" } else { file };
+                        self.virpc.start_trace(filename)?;
+                        Ok(vec![format!("tracing to {}", filename)])
+                    }
+                    "off" => {
+                        self.virpc.stop_trace();
+                        Ok(vec!["tracing stopped".to_string()])
+                    }
+                    _ => Ok(vec![format!("tracing is {}, trace on [file] or trace off", if self.virpc.is_tracing() { "on" } else { "off" })]),
+                }
+            }
+            _ if word == "x" || word.starts_with("x/") => self.examine(&word[1..], rest),
+            _ => Err(format!("don't know '{}', type help for the commands", word)),
+        }
+    }
+
+    //x/<count><unit> memory dump, 16 bytes or 4 words a line
+    fn examine(&mut self, format : &str, rest : &str) -> Result<Vec<String>, String> {
+        let format = format.trim_start_matches('/');
+        let digits = format.chars().take_while(|c| c.is_ascii_digit()).count();
+        let count = match digits {
+            0 => 16,
+            _ => format[..digits].parse::<u32>().map_err(|_| format!("'{}' isn't a count", &format[..digits]))?.min(4096),
+        };
+        let size = match &format[digits..] {
+            "" | "x" | "b" => 1,
+            "w" => 4,
+            unit => return Err(format!("unit '{}' isn't x or w", unit)),
+        };
+        let adr = self.console_value(rest)?;
+        let mem = self.virpc.memory.borrow();
+        let per_line = 16 / size;
+        let mut lines = Vec::new();
+        let mut n = 0;
+        while n < count {
+            let start = adr.wrapping_add(n * size);
+            let mut text = format!("${:08X}:", start);
+            let mut chars = String::new();
+            for i in n..count.min(n + per_line) {
+                let a = adr.wrapping_add(i * size);
+                if size == 1 {
+                    let byte = mem.peek_byte(a);
+                    text.push_str(&format!(" {:02X}", byte));
+                    chars.push(EBCDIC[byte as usize]);
+                }
+                else {
+                    text.push_str(&format!(" {:08X}", mem.peek_int_le(a)));
+                }
+            }
+            if size == 1 {
+                text.push_str(&format!("{}  {}", " ".repeat(3 * (per_line - chars.len() as u32) as usize), chars));
+            }
+            lines.push(text);
+            n += per_line;
+        }
+        Ok(lines)
+    }
+
+    //move the edit line to the instruction at an address
+    fn goto_code(&mut self, adr : u32) {
+        let mut lpc = 0;
+        let mut line = 0;
+        while lpc < adr && lpc < cpu::BSS {
+            lpc = self.cpu_reader.borrow_mut().disassemble(lpc);
+            line += 1;
+        }
+        self.cpu_reader.borrow_mut().set_pc(self.edit_pc);
+        self.edit_line = line;
+        self.reset_edit();
+    }
+
+    //disassembled line with a breakpoint marker in front: * enabled, o disabled
+    fn code_line(&mut self, adr : u32) -> String {
+        let marker = match self.virpc.breakpoints().get(adr) {
+            Some(b) if b.enabled => '*',
+            Some(_) => 'o',
+            None => ' ',
+        };
+        format!("{}{}", marker, self.cpu_reader.borrow_mut().instruction_to_text())
+    }
+
     //modify values based on sub-meny
     fn modify(&mut self) {
         //take all current settings from menu 1, 2 and 3
@@ -1027,6 +1917,7 @@ impl Windows {
     // key event handler
     ///////////////////////////////////////////
     pub fn handle_keys(&mut self, ch : i32) {
+        self.message.clear();
         match ch {
             0x09 => {
                 self.focus += 1;
@@ -1045,6 +1936,30 @@ impl Windows {
                 self.screen_height = 0;//trigger an refresh_screen
                 self.resize_check();//show the edited value
             }
+            0x3a => {//<: console>
+                self.console();
+            }
+            0x10a => {//<F2 snapshot>
+                self.snapshot_dialog();
+            }
+            0x10b => {//<F3 run back>
+                //step back to the previous breakpoint
+                if self.virpc.journal_len() == 0 {
+                    self.message = "nothing to step back to".to_string();
+                }
+                self.virpc.run_back();
+                self.run_program = false;
+                self.refresh_fast();
+            }
+            0x10c => {//<F4 step back>
+                //undo the last instruction
+                if self.virpc.journal_len() == 0 {
+                    self.message = "nothing to step back to".to_string();
+                }
+                self.virpc.step_back();
+                self.run_program = false;
+                self.refresh_fast();
+            }
             0x10d => {//<F5 run/pause>
                 //toggle running/stop
                 if self.run_program == false {
@@ -1064,17 +1979,48 @@ impl Windows {
                 self.refresh_fast();
                 self.refresh_code();
             }
+            0x10f => {//<F7 record>
+                //toggle recording of every frame to record00000.png, record00001.png, ...
+                if self.virpc.is_recording() {
+                    self.virpc.stop_recording();
+                }
+                else {
+                    self.virpc.start_recording("record", 1);
+                }
+                self.refresh_fast();
+            }
             0x110 => {//<F8 step>
                 //perform a cpu-step
-                self.virpc.continue_cpu();
-                self.virpc.run();
-                self.virpc.stop();
+                self.virpc.step();
                 self.refresh_fast();
                 self.refresh_code();
             }
+            0x112 => {//<F10 step over>
+                //a CLL runs until its call returned
+                self.virpc.step_over();
+                self.run_program = true;
+                self.refresh_fast();
+            }
+            0x113 => {//<F11 step out>
+                //run until the current call returns
+                match self.virpc.step_out() {
+                    Ok(()) => self.run_program = true,
+                    Err(why) => self.message = why,
+                }
+                self.refresh_fast();
+            }
             0x111 => {//<F9 breakpoint>
-                //set virpc::toggle-breakpoint
-                self.virpc.breakpoint(self.edit_pc);
+                //set or clear a breakpoint on the edited line
+                self.virpc.toggle_breakpoint(self.edit_pc);
+                self.refresh_code();
+            }
+            0x114 => {//<F12 screenshot>
+                //save the screen to screenshot000.png, screenshot001.png, ...
+                let filename = format!("screenshot{:03}.png", self.screenshot_count);
+                match self.virpc.screenshot(&filename) {
+                    Ok(()) => self.screenshot_count += 1,
+                    Err(why) => self.show_message(&format!("{}: {}", filename, why)),
+                }
             }
             _ => {
                 match self.focus {
@@ -1126,6 +2072,27 @@ impl Windows {
             0x66 => {
                 self.search_label();
             }
+            0x62 => {
+                self.breakpoint_list();
+            }
+            0x70 => {
+                self.profile_report();
+            }
+            0x76 => {
+                self.coverage_dialog();
+            }
+            0x72 => {//r, run to the edited line
+                self.virpc.run_to(self.edit_pc);
+                self.run_program = true;
+                self.refresh_fast();
+            }
+            0x6e => {//n, run a number of instructions
+                if let Some(count) = self.input_count() {
+                    self.virpc.run_instructions(count);
+                    self.run_program = true;
+                }
+                self.refresh_fast();
+            }
             _ => {
             }
         }
@@ -1155,6 +2122,14 @@ impl Windows {
                     self.refresh_screen();                    
                 }
             }
+            0x77 => {//w, watch the label of this entry
+                let index = item_index(current_item(self.menu2)) as u32;
+                let adr = self.cpu_reader.borrow_mut().get_data_value(index);
+                let lbl = self.cpu_reader.borrow_mut().get_label(adr);
+                if let Some(lbl) = lbl {
+                    self.watch_dialog(lbl.address, lbl.size);
+                }
+            }
             _ => {
                 menu_driver(self.menu2, ch);
                 wrefresh(self.win3);
@@ -1288,6 +2263,14 @@ impl Windows {
                                 col = begin + (col*3);
                             }
                         }
+                        0x77 if hex => {//w, watch the byte under the cursor
+                            let addr = self.hex_view_offset + (((col-begin)/3) + ((row-1)*(((w+3)-begin)/3))) as u32;
+                            self.watch_dialog(addr, 1);
+                        }
+                        0x6c if hex => {//l, who last wrote the byte under the cursor
+                            let addr = self.hex_view_offset + (((col-begin)/3) + ((row-1)*(((w+3)-begin)/3))) as u32;
+                            self.show_last_write(addr);
+                        }
                         _ => {
                             let key = (ch as u8) as char;
                             if (key.is_ascii() && !hex) || (hex && key.is_ascii_hexdigit()) {
@@ -1336,6 +2319,12 @@ impl Windows {
             0x66 => {
                 self.search_label();
             }
+            0x77 => {//w, watch the highlighted label
+                self.watch_dialog(self.mem_highlight, self.mem_highlight_size);
+            }
+            0x6c => {//l, who last wrote the highlighted label
+                self.show_last_write(self.mem_highlight);
+            }
             _ => {
                 self.refresh_memview();
             }
@@ -25,12 +25,29 @@ use std::thread;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicIsize, Ordering};
 
+const UI_FREQ: f64 = 10.0;  // ncurses refreshes per second
+const UI_TICK_MS: u64 = 20; // pause between editor updates, the emulator has its own thread
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     let mut prg_to_load  = "test.prg".to_string();
     let mut debugger_on  = false;
     let mut window_scale = Scale::X2;
+    let mut screenshot   = String::new();
+    let mut load         = String::new();
+    let mut save         = String::new();
+    let mut record       = String::new();
+    let mut record_every = 1;
+    let mut speed        = 0;
+    let mut benchmark    = 0;
+    let mut frames       = 0;
+    let mut journal      = 0;
+    let mut trace        = String::new();
+    let mut coverage     = String::new();
+    let mut gdb_port     = 0;
+    let mut dap_port     = 0;
+    let mut trace_filter = virpc::trace::Filter::new();
 
     // process cmd line params
     for i in 1..args.len() {
@@ -40,21 +57,156 @@ fn main() {
         else if args[i] == "x2" {
             window_scale = Scale::X2;
         }
+        else if args[i].starts_with("screenshot=") { // save the screen on exit
+            screenshot = args[i]["screenshot=".len()..].to_string();
+        }
+        else if args[i].starts_with("load=") { // continue from a machine snapshot
+            load = args[i]["load=".len()..].to_string();
+        }
+        else if args[i].starts_with("save=") { // save a machine snapshot on exit
+            save = args[i]["save=".len()..].to_string();
+        }
+        else if args[i].starts_with("record=") { // save frames as numbered images
+            record = args[i]["record=".len()..].to_string();
+        }
+        else if args[i].starts_with("every=") { // record only every nth frame
+            record_every = args[i]["every=".len()..].parse().unwrap_or(1);
+        }
+        else if args[i].starts_with("speed=") { // instructions per second
+            speed = args[i]["speed=".len()..].parse().unwrap_or(0);
+        }
+        else if args[i] == "journal" { // keep instructions to step back, off by default
+            journal = virpc::journal::DEFAULT_SIZE;
+        }
+        else if args[i].starts_with("journal=") { // instructions kept to step back, 0 for none
+            journal = args[i]["journal=".len()..].parse().unwrap_or(virpc::journal::DEFAULT_SIZE);
+        }
+        else if args[i].starts_with("trace=") { // log every executed instruction, .csv or json lines
+            trace = args[i]["trace=".len()..].to_string();
+        }
+        else if args[i].starts_with("trace_from=") { // only instructions from this address
+            trace_filter.from = number_arg(&args[i]["trace_from=".len()..]).unwrap_or(0);
+        }
+        else if args[i].starts_with("trace_to=") { // up to and including this address
+            trace_filter.to = number_arg(&args[i]["trace_to=".len()..]).unwrap_or(u32::MAX);
+        }
+        else if args[i].starts_with("trace_skip=") { // start after this many instructions
+            trace_filter.skip = args[i]["trace_skip=".len()..].parse().unwrap_or(0);
+        }
+        else if args[i].starts_with("trace_count=") { // stop after this many records
+            trace_filter.count = args[i]["trace_count=".len()..].parse().unwrap_or(0);
+        }
+        else if args[i].starts_with("coverage=") { // collect coverage, the report is saved on exit
+            coverage = args[i]["coverage=".len()..].to_string();
+        }
+        else if args[i].starts_with("gdb=") { // gdb remote protocol on this localhost port
+            gdb_port = args[i]["gdb=".len()..].parse().unwrap_or(0);
+        }
+        else if args[i].starts_with("dap=") { // debug adapter protocol on this localhost port
+            dap_port = args[i]["dap=".len()..].parse().unwrap_or(0);
+        }
+        else if args[i].starts_with("frames=") { // run this many frames without windows and quit
+            frames = args[i]["frames=".len()..].parse().unwrap_or(0);
+        }
+        else if args[i] == "benchmark" { // measure the interpreter speed and quit
+            benchmark = 10000000;
+        }
+        else if args[i].starts_with("benchmark=") { // for this many instructions
+            benchmark = args[i]["benchmark=".len()..].parse().unwrap_or(10000000);
+        }
         else if args[i].ends_with(".prg") {
             prg_to_load = args[i].clone();
         }
     }
-    let mut virpc = virpc::Virpc::new(window_scale, debugger_on, &prg_to_load);
+    if benchmark > 0 {
+        virpc::benchmark::run(&prg_to_load, benchmark);
+        return;
+    }
+    if frames > 0 {
+        if let Err(why) = virpc::headless::run(&prg_to_load, frames, &screenshot, &record, record_every) {
+            println!("{}", why);
+        }
+        return;
+    }
+
+    // the file is created here, so a bad path is reported before the screen is taken over
+    let trace_file = if trace.is_empty() { None } else {
+        match std::fs::File::create(&trace) {
+            Ok(file) => Some(file),
+            Err(why) => {
+                println!("Couldn't create {}: {}", trace, why);
+                return;
+            }
+        }
+    };
+
+    // the port is taken here, so one that is in use is reported before the screen is taken over
+    let gdb_server = if gdb_port == 0 { None } else {
+        match virpc::gdb::Server::bind(gdb_port) {
+            Ok(server) => Some(server),
+            Err(why) => {
+                println!("Couldn't listen on port {}: {}", gdb_port, why);
+                return;
+            }
+        }
+    };
+
+    let dap_server = if dap_port == 0 { None } else {
+        match virpc::dap::Server::bind(dap_port) {
+            Ok(server) => Some(server),
+            Err(why) => {
+                println!("Couldn't listen on port {}: {}", dap_port, why);
+                return;
+            }
+        }
+    };
+
+    // a loaded snapshot brings the labels for the disassembly in the trace
+    let trace_labels = if trace.is_empty() || load.is_empty() { Vec::new() } else {
+        virpc::snapshot::Snapshot::load(&load).and_then(|snap| virpc::snapshot::load_labels(&snap)).unwrap_or_default()
+    };
+
+    let covering = !coverage.is_empty();
+
+    // the machine runs on its own thread, so a slow terminal doesn't slow it down
+    let display = virpc::display::Display::new(window_scale, debugger_on);
+    let machine = virpc::machine::Machine::spawn(Some(display), move || {
+        let mut virpc = virpc::Virpc::new(&prg_to_load);
+        virpc.set_speed(speed);
+        virpc.set_journal(journal);
+        if !record.is_empty() {
+            virpc.start_recording(&record, record_every);
+        }
+        virpc.set_coverage(covering);
+        if let Some(server) = gdb_server {
+            virpc.start_gdb(server);
+        }
+        if let Some(server) = dap_server {
+            virpc.start_dap(server);
+        }
+        if let Some(file) = trace_file {
+            let format = virpc::trace::Format::from_filename(&trace);
+            if let Ok(mut tracer) = virpc::trace::Tracer::new(file, format, trace_filter) {
+                tracer.set_labels(trace_labels);
+                virpc.start_trace(tracer);
+            }
+        }
+        virpc
+    });
 
     let asmcpu = cpu::CPU::new_shared(0xFF00);
-    virpc.reset();
-    virpc.run();
-    asmcpu.borrow_mut().set_references(virpc.memory.clone());
-    let mut _windows : Windows = Windows::new(asmcpu, virpc);
+    asmcpu.borrow_mut().set_references(machine.memory.clone());
+    let mut _windows : Windows = Windows::new(asmcpu, machine);
+    if !load.is_empty() {
+        if let Err(why) = _windows.load_snapshot(&load) {
+            _windows.show_message(&format!("{}: {}", load, why));
+        }
+    }
 
     let shared_ch = Arc::new(AtomicIsize::new(0));
     let key_handle = keyboard_thread(shared_ch.clone());
 
+    let mut ui_clock = virpc::clock::Clock::new(UI_FREQ);
     let mut ch = 0;
     while ch != 27 as i32 { // ESC pressed, so quit
         //load new char
@@ -62,21 +214,43 @@ fn main() {
         //reset ch, so that nex one can be loaded
         shared_ch.store(0,Ordering::Relaxed);
 
-        //run emulator
+        //exchange memory and commands with the emulator
         _windows.run_virpc();
 
-        //run IDE
-        _windows.refresh_fast();
-        _windows.resize_check();
+        //run IDE, at its own pace
+        if ui_clock.tick() {
+            _windows.refresh_fast();
+            _windows.resize_check();
+        }
         //handle keys
         if ch > 0 {
             _windows.handle_keys(ch);
         }
         //wait a bit
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        std::thread::sleep(std::time::Duration::from_millis(UI_TICK_MS));
     }
     key_handle.join().unwrap();
+    let snapshot_saved = if save.is_empty() { Ok(()) } else { _windows.save_snapshot(&save) };
+    let coverage_saved = if coverage.is_empty() { Ok(()) } else { _windows.save_coverage(&coverage) };
+    let saved = _windows.quit_virpc(&screenshot);
     _windows.destroy();
+    if let Err(why) = snapshot_saved {
+        println!("Couldn't save {}: {}", save, why);
+    }
+    if let Err(why) = coverage_saved {
+        println!("Couldn't save {}: {}", coverage, why);
+    }
+    if let Err(why) = saved {
+        println!("{}", why);
+    }
+}
+
+// decimal, or hexadecimal with 0x
+fn number_arg(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
 }
 
 fn keyboard_thread(ch : Arc<AtomicIsize>) -> thread::JoinHandle<()> {
@@ -0,0 +1,354 @@
+// just enough JSON for the trace and the debug adapter: a value type, a parser and compact output
+use std::fmt;
+
+const MAX_DEPTH: usize = 64; // deeper arrays and objects are an error, not a stack overflow
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>), // in the order of the text
+}
+
+static NULL: Value = Value::Null;
+
+impl Value {
+    pub fn parse(text: &str) -> Result<Value, String> {
+        let mut parser = Parser { text: text.as_bytes(), pos: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.text.len() {
+            return Err(format!("unexpected text at {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    // the field of an object, Null when it isn't there
+    pub fn get(&self, key: &str) -> &Value {
+        match *self {
+            Value::Object(ref fields) => fields.iter().find(|f| f.0 == key).map_or(&NULL, |f| &f.1),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Number(n) if n.fract() == 0.0 => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    // the elements of an array, none for anything else
+    pub fn as_array(&self) -> &[Value] {
+        match *self {
+            Value::Array(ref elements) => elements,
+            _ => &[],
+        }
+    }
+}
+
+// an object from its fields
+pub fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value { Value::Bool(b) }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Value { Value::Number(n as f64) }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value { Value::Number(n as f64) }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Value { Value::Number(n as f64) }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value { Value::String(s.to_string()) }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value { Value::String(s) }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(elements: Vec<Value>) -> Value { Value::Array(elements) }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            // whole numbers without a fraction, like they came in
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", n as i64),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(ref s) => write!(f, "\"{}\"", escape(s)),
+            Value::Array(ref elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "\"{}\":{}", escape(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// the inside of a JSON string
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    depth: usize, // arrays and objects the parser is in
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') | Some(b'[') => {
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    return Err(format!("nested too deep at {}", self.pos));
+                }
+                let value = if self.peek() == Some(b'{') { self.object() } else { self.array() };
+                self.depth -= 1;
+                value
+            }
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b't') => self.word("true", Value::Bool(true)),
+            Some(b'f') => self.word("false", Value::Bool(false)),
+            Some(b'n') => self.word("null", Value::Null),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => Err(format!("expected a value at {}", self.pos)),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(format!("expected a key at {}", self.pos));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => (),
+                Some(b'}') => return Ok(Value::Object(fields)),
+                _ => return Err(format!("expected , or }} at {}", self.pos)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.pos += 1;
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            elements.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => (),
+                Some(b']') => return Ok(Value::Array(elements)),
+                _ => return Err(format!("expected , or ] at {}", self.pos)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let c = match self.next() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode()?,
+                        _ => return Err(format!("bad escape at {}", self.pos)),
+                    };
+                    let mut buffer = [0u8; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                Some(b) => bytes.push(b),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| "string isn't utf-8".to_string())
+    }
+
+    // the code after \u, with the second half of a surrogate pair
+    fn unicode(&mut self) -> Result<char, String> {
+        let first = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&first) && self.text[self.pos..].starts_with(b"\\u") {
+            self.pos += 2;
+            let second = self.hex4()?;
+            0x10000 + ((first - 0xD800) << 10) + (second.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            first
+        };
+        Ok(char::from_u32(code).unwrap_or('\u{FFFD}'))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or("short \\u escape")?;
+        self.pos += 4;
+        std::str::from_utf8(digits).ok()
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| format!("bad \\u escape at {}", self.pos))
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos]).ok()
+            .and_then(|n| n.parse().ok())
+            .map(Value::Number)
+            .ok_or_else(|| format!("bad number at {}", start))
+    }
+
+    fn word(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(format!("expected {} at {}", word, self.pos))
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), String> {
+        if self.next() == Some(b) {
+            Ok(())
+        } else {
+            Err(format!("expected {} at {}", b as char, self.pos))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek();
+        self.pos += 1;
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_writes_back() {
+        let text = r#"{"a":[1,-2.5,true,null],"b":"x\"y","c":{}}"#;
+        let value = Value::parse(text).unwrap();
+        assert_eq!(value.get("a").as_array().len(), 4);
+        assert_eq!(value.get("b").as_str(), Some("x\"y"));
+        assert_eq!(value.get("missing"), &Value::Null);
+        assert_eq!(value.to_string(), text);
+    }
+
+    #[test]
+    fn escapes() {
+        let value = Value::parse(r#""\n\t\/\u00e9\ud83d\ude00""#).unwrap();
+        assert_eq!(value.as_str(), Some("\n\t/\u{e9}\u{1F600}"));
+        assert_eq!(Value::from("a\"b\\c\u{1}").to_string(), r#""a\"b\\c\u0001""#);
+        assert!(Value::parse(r#""\x""#).is_err());
+        assert!(Value::parse(r#""\u12""#).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        for text in ["", "{", "[1,]", "[1 2]", r#"{"a" 1}"#, r#"{1:2}"#, r#""open"#, "tru", "1 2", "-"] {
+            assert!(Value::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn limits_the_depth() {
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Value::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Value::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Value::parse(&"[{\"a\":".repeat(100000)).is_err());
+    }
+}
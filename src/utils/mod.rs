@@ -4,6 +4,8 @@ use std::fs::File;
 use std::io::SeekFrom;
 use std::path::Path;
 
+pub mod json;
+
 
 // helper macros to easily extract references from Option<RefCell<...>>
 macro_rules! as_ref {
@@ -37,19 +39,6 @@ pub fn open_file(filename: &str, offset: u64) -> Vec<u8> {
 }
 
 
-// set 8 consecutive buffer elements to single value for faster update of
-// a single 8-pixel screen chunk
-pub fn memset8(buffer: &mut [u32], start: usize, value: u32) {
-    buffer[start]   = value;
-    buffer[start+1] = buffer[start];
-    buffer[start+2] = buffer[start];
-    buffer[start+3] = buffer[start];
-    buffer[start+4] = buffer[start];
-    buffer[start+5] = buffer[start];
-    buffer[start+6] = buffer[start];
-    buffer[start+7] = buffer[start];
-}
-
 pub fn fetch_c64_color_rgba(idx: u8) -> u32 {
     // palette RGB values copied from WinVICE
     match idx & 0x0F {
@@ -0,0 +1,31 @@
+// measures the speed of the interpreter on a program, without video or the editor
+extern crate time;
+
+use crate::virpc;
+use crate::virpc::cpu;
+use crate::virpc::memory;
+
+// run the program for a number of instructions, once decoding every instruction and once
+// with the instruction cache, and print the MIPS of both
+pub fn run(prg_file: &str, instructions: u32) {
+    let mut mips = [0.0; 2];
+    for (i, cached) in [false, true].iter().enumerate() {
+        let memory = memory::Memory::new_shared();
+        let cpu = cpu::CPU::new_shared(virpc::PC_REG);
+        cpu.borrow_mut().set_references(memory.clone());
+        cpu.borrow_mut().reset();
+        cpu.borrow_mut().set_cache(*cached);
+        virpc::load_prg(&memory, prg_file);
+
+        let start = time::precise_time_s();
+        for _ in 0..instructions {
+            cpu.borrow_mut().update();
+        }
+        let seconds = time::precise_time_s() - start;
+
+        mips[i] = instructions as f64 / seconds / 1000000.0;
+        println!("{:<10} {} instructions in {:.3} s, {:.2} MIPS",
+                 if *cached { "cached:" } else { "uncached:" }, instructions, seconds, mips[i]);
+    }
+    println!("speedup:   {:.2}x", mips[1] / mips[0]);
+}
@@ -0,0 +1,308 @@
+// blitter, draws into a framebuffer with one byte per pixel while the cpu keeps running
+use crate::virpc::memory;
+use crate::virpc::snapshot;
+use crate::virpc::video;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io;
+
+pub type BlitterShared = Rc<RefCell<Blitter>>;
+
+// blitter registers, part of the video register block
+pub const BLIT_DST: u32 = 0xF440;       // framebuffer to draw in (int)
+pub const BLIT_PITCH: u32 = 0xF444;     // bytes per line of the framebuffer, pixels beyond are clipped (int)
+pub const BLIT_SRC: u32 = 0xF448;       // top left pixel of the copy source (int)
+pub const BLIT_SRC_PITCH: u32 = 0xF44C; // bytes per line of the copy source (int)
+pub const BLIT_X: u32 = 0xF450;         // top left, or start of a line (int)
+pub const BLIT_Y: u32 = 0xF454;         // (int)
+pub const BLIT_W: u32 = 0xF458;         // size of a rectangle or copy (int)
+pub const BLIT_H: u32 = 0xF45C;         // (int)
+pub const BLIT_X2: u32 = 0xF460;        // end of a line (int)
+pub const BLIT_Y2: u32 = 0xF464;        // (int)
+pub const BLIT_COLOR: u32 = 0xF468;     // colour to draw with (byte)
+pub const BLIT_KEY: u32 = 0xF469;       // source colour that is skipped by a copy (byte)
+pub const BLIT_CHAR: u32 = 0xF46A;      // character code to draw from the font (byte)
+pub const BLIT_COMMAND: u32 = 0xF46B;   // 1 = rectangle, 2 = line, 3 = copy, 4 = character, cleared when accepted (byte)
+pub const BLIT_STATUS: u32 = 0xF46C;    // bit 0: busy (byte)
+pub const BLIT_LINES: u32 = 0xF470;     // lines of the framebuffer, pixels below are clipped (int)
+
+// pixels drawn for every cycle the blitter owns the bus
+const BLIT_PIXELS_PER_CYCLE: u32 = 4;
+
+pub enum BlitCommand {
+    Rectangle,
+    Line,
+    Copy,
+    Character,
+}
+
+pub struct Blitter {
+    mem_ref: Option<memory::MemShared>,
+    busy: bool,
+    command: BlitCommand,
+    dst: u32,
+    pitch: u32,
+    lines: u32,
+    src: u32,
+    src_pitch: u32,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+    x2: i32,
+    y2: i32,
+    color: u8,
+    key: u8,
+    glyph: u32,  // address of the character in the font
+    col: i32,    // progress of rectangle, copy and character
+    row: i32,
+    err: i32,    // bresenham error term of a line
+    dx: i32,     // distance and direction of a line, fixed when it starts
+    dy: i32,
+    sx: i32,
+    sy: i32,
+}
+
+impl Blitter {
+    pub fn new_shared() -> BlitterShared {
+        Rc::new(RefCell::new(Blitter {
+            mem_ref: None,
+            busy: false,
+            command: BlitCommand::Rectangle,
+            dst: 0,
+            pitch: 0,
+            lines: 0,
+            src: 0,
+            src_pitch: 0,
+            x: 0,
+            y: 0,
+            w: 0,
+            h: 0,
+            x2: 0,
+            y2: 0,
+            color: 0,
+            key: 0,
+            glyph: 0,
+            col: 0,
+            row: 0,
+            err: 0,
+            dx: 0,
+            dy: 0,
+            sx: 0,
+            sy: 0,
+        }))
+    }
+
+    pub fn set_references(&mut self, memref: memory::MemShared) {
+        self.mem_ref = Some(memref);
+    }
+
+    pub fn reset(&mut self) {
+        self.busy = false;
+        let mut mem = as_mut!(self.mem_ref);
+        mem.write_int_le(BLIT_DST, video::FRAMEBUFFER);
+        mem.write_int_le(BLIT_PITCH, 300);
+        mem.write_int_le(BLIT_LINES, 200);
+        mem.write_byte(BLIT_COMMAND, 0);
+        mem.write_byte(BLIT_STATUS, 0);
+    }
+
+    // the command in progress, the registers are saved with memory
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.bool(self.busy);
+        w.u8(match self.command {
+            BlitCommand::Rectangle => 0,
+            BlitCommand::Line => 1,
+            BlitCommand::Copy => 2,
+            BlitCommand::Character => 3,
+        });
+        for value in [self.dst, self.pitch, self.src, self.src_pitch] {
+            w.u32(value);
+        }
+        for value in [self.x, self.y, self.w, self.h, self.x2, self.y2] {
+            w.i32(value);
+        }
+        w.u8(self.color);
+        w.u8(self.key);
+        w.u32(self.glyph);
+        w.i32(self.col);
+        w.i32(self.row);
+        w.i32(self.err);
+        for value in [self.dx, self.dy, self.sx, self.sy] {
+            w.i32(value);
+        }
+        w.u32(self.lines);
+    }
+
+    pub fn load(&mut self, r: &mut snapshot::Reader) -> io::Result<()> {
+        self.busy = r.bool()?;
+        self.command = match r.u8()? {
+            0 => BlitCommand::Rectangle,
+            1 => BlitCommand::Line,
+            2 => BlitCommand::Copy,
+            _ => BlitCommand::Character,
+        };
+        self.dst = r.u32()?;
+        self.pitch = r.u32()?;
+        self.src = r.u32()?;
+        self.src_pitch = r.u32()?;
+        self.x = r.i32()?;
+        self.y = r.i32()?;
+        self.w = r.i32()?;
+        self.h = r.i32()?;
+        self.x2 = r.i32()?;
+        self.y2 = r.i32()?;
+        self.color = r.u8()?;
+        self.key = r.u8()?;
+        self.glyph = r.u32()?;
+        self.col = r.i32()?;
+        self.row = r.i32()?;
+        self.err = r.i32()?;
+        if r.at_end() {
+            // older snapshots: a line continues from where it is, everything can be drawn
+            self.line_setup();
+            self.lines = u32::MAX;
+            return Ok(());
+        }
+        self.dx = r.i32()?;
+        self.dy = r.i32()?;
+        self.sx = r.i32()?;
+        self.sy = r.i32()?;
+        self.lines = r.u32()?;
+        Ok(())
+    }
+
+    // advance the blitter by a single cycle
+    pub fn update(&mut self) {
+        if !self.busy {
+            let command = as_mut!(self.mem_ref).read_byte(BLIT_COMMAND);
+            if command == 0 {
+                return;
+            }
+            self.start(command);
+            return; // accepting a command costs a cycle
+        }
+
+        for _ in 0..BLIT_PIXELS_PER_CYCLE {
+            if !self.step() {
+                self.busy = false;
+                as_mut!(self.mem_ref).write_byte(BLIT_STATUS, 0);
+                return;
+            }
+        }
+    }
+
+    // *** private functions *** //
+
+    fn start(&mut self, command: u8) {
+        let mut mem = as_mut!(self.mem_ref);
+        mem.write_byte(BLIT_COMMAND, 0);
+        self.command = match command {
+            1 => BlitCommand::Rectangle,
+            2 => BlitCommand::Line,
+            3 => BlitCommand::Copy,
+            4 => BlitCommand::Character,
+            _ => return, // unknown commands are ignored
+        };
+        self.dst = mem.read_int_le(BLIT_DST);
+        self.pitch = mem.read_int_le(BLIT_PITCH);
+        self.lines = mem.read_int_le(BLIT_LINES);
+        self.src = mem.read_int_le(BLIT_SRC);
+        self.src_pitch = mem.read_int_le(BLIT_SRC_PITCH);
+        self.x = mem.read_int_le(BLIT_X) as i32;
+        self.y = mem.read_int_le(BLIT_Y) as i32;
+        self.w = mem.read_int_le(BLIT_W) as i32;
+        self.h = mem.read_int_le(BLIT_H) as i32;
+        self.x2 = mem.read_int_le(BLIT_X2) as i32;
+        self.y2 = mem.read_int_le(BLIT_Y2) as i32;
+        self.color = mem.read_byte(BLIT_COLOR);
+        self.key = mem.read_byte(BLIT_KEY);
+        self.glyph = mem.read_int_le(video::VIDEO_FONT).wrapping_add(8 * mem.read_byte(BLIT_CHAR) as u32);
+        self.col = 0;
+        self.row = 0;
+        self.busy = true;
+        mem.write_byte(BLIT_STATUS, 0x01);
+        drop(mem);
+
+        match self.command {
+            BlitCommand::Line => {
+                self.line_setup();
+                self.err = self.dx.wrapping_sub(self.dy);
+            }
+            BlitCommand::Character => {
+                self.w = 8;
+                self.h = 8;
+            }
+            _ => {}
+        }
+    }
+
+    // draw the next pixel, returns false when the command is finished
+    fn step(&mut self) -> bool {
+        match self.command {
+            BlitCommand::Line => return self.step_line(),
+            BlitCommand::Rectangle => {
+                if self.row < self.h && self.col < self.w {
+                    self.plot(self.x.wrapping_add(self.col), self.y.wrapping_add(self.row), self.color);
+                }
+            }
+            BlitCommand::Copy => {
+                if self.row < self.h && self.col < self.w {
+                    let src = self.src.wrapping_add((self.row as u32).wrapping_mul(self.src_pitch)).wrapping_add(self.col as u32);
+                    let value = as_mut!(self.mem_ref).read_byte(src);
+                    if value != self.key {
+                        self.plot(self.x.wrapping_add(self.col), self.y.wrapping_add(self.row), value);
+                    }
+                }
+            }
+            BlitCommand::Character => {
+                let bits = as_mut!(self.mem_ref).read_byte(self.glyph.wrapping_add(self.row as u32));
+                if bits & (0x80 >> self.col) != 0 {
+                    self.plot(self.x.wrapping_add(self.col), self.y.wrapping_add(self.row), self.color);
+                }
+            }
+        }
+
+        self.col += 1;
+        if self.col >= self.w {
+            self.col = 0;
+            self.row += 1;
+        }
+        self.row < self.h && self.w > 0
+    }
+
+    // bresenham, one pixel per step
+    fn step_line(&mut self) -> bool {
+        self.plot(self.x, self.y, self.color);
+        if self.x == self.x2 && self.y == self.y2 {
+            return false;
+        }
+
+        let e2 = self.err.wrapping_mul(2);
+        if e2 > self.dy.wrapping_neg() {
+            self.err = self.err.wrapping_sub(self.dy);
+            self.x = self.x.wrapping_add(self.sx);
+        }
+        if e2 < self.dx {
+            self.err = self.err.wrapping_add(self.dx);
+            self.y = self.y.wrapping_add(self.sy);
+        }
+        true
+    }
+
+    // the distances and directions from the current point to the end of the line
+    fn line_setup(&mut self) {
+        self.dx = self.x2.wrapping_sub(self.x).wrapping_abs();
+        self.dy = self.y2.wrapping_sub(self.y).wrapping_abs();
+        self.sx = if self.x < self.x2 { 1 } else { -1 };
+        self.sy = if self.y < self.y2 { 1 } else { -1 };
+    }
+
+    fn plot(&mut self, x: i32, y: i32, color: u8) {
+        if x < 0 || y < 0 || x as u32 >= self.pitch || y as u32 >= self.lines {
+            return;
+        }
+        let adr = self.dst.wrapping_add((y as u32).wrapping_mul(self.pitch)).wrapping_add(x as u32);
+        as_mut!(self.mem_ref).write_byte(adr, color);
+    }
+}
@@ -0,0 +1,361 @@
+// breakpoint table, every breakpoint can be disabled, counts its hits and can have a condition
+// over memory and registers, like "[reg0] == 5" or "b[0xE010] != 0"
+use crate::virpc::cpu;
+use crate::virpc::memory;
+use crate::virpc::snapshot;
+use std::io;
+use crate::virpc::PC_REG;
+
+#[derive(Clone, PartialEq)]
+pub struct Breakpoint {
+    pub address: u32,
+    pub enabled: bool,
+    pub hits: u32,         // times execution stopped here
+    pub condition: String, // as typed, empty when it always stops
+    parsed: Option<Condition>,
+}
+
+#[derive(Clone, Default, PartialEq)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>, // sorted on address
+    temporary: Option<(u32, usize)>, // stops once at this address, at most this many calls deep
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints { list: Vec::new(), temporary: None }
+    }
+
+    pub fn list(&self) -> &[Breakpoint] {
+        &self.list
+    }
+
+    pub fn get(&self, address: u32) -> Option<&Breakpoint> {
+        match self.find(address) {
+            Ok(i) => Some(&self.list[i]),
+            Err(_) => None,
+        }
+    }
+
+    // add an unconditional breakpoint, or remove the one that is there
+    pub fn toggle(&mut self, address: u32) {
+        match self.find(address) {
+            Ok(i) => { self.list.remove(i); }
+            Err(i) => self.list.insert(i, Breakpoint {
+                address,
+                enabled: true,
+                hits: 0,
+                condition: String::new(),
+                parsed: None,
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, address: u32) {
+        if let Ok(i) = self.find(address) {
+            self.list.remove(i);
+        }
+    }
+
+    pub fn enable(&mut self, address: u32, enabled: bool) {
+        if let Ok(i) = self.find(address) {
+            self.list[i].enabled = enabled;
+        }
+    }
+
+    // set the condition of a breakpoint, adding it when needed. an empty condition always stops
+    pub fn set_condition(&mut self, address: u32, condition: &str) -> Result<(), String> {
+        let parsed = parse_condition(condition)?;
+        if self.find(address).is_err() {
+            self.toggle(address);
+        }
+        if let Ok(i) = self.find(address) {
+            self.list[i].condition = String::from(condition.trim());
+            self.list[i].parsed = parsed;
+        }
+        Ok(())
+    }
+
+    // stop once at an address, for step over, step out and run to cursor. calls made after it
+    // was set don't stop on it, so a recursive call returning to the same place runs on
+    pub fn set_temporary(&mut self, address: u32, depth: usize) {
+        self.temporary = Some((address, depth));
+    }
+
+    pub fn clear_temporary(&mut self) {
+        self.temporary = None;
+    }
+
+    // anything to check before the instructions run
+    pub fn is_active(&self) -> bool {
+        !self.list.is_empty() || self.temporary.is_some()
+    }
+
+    // true when execution got to the temporary breakpoint at the call depth it was set at, or less
+    pub fn check_temporary(&mut self, pc: u32, depth: usize) -> bool {
+        match self.temporary {
+            Some((address, max)) if address == pc && depth <= max => {
+                self.temporary = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // true when execution has to stop before the instruction at pc, the hit is counted
+    pub fn check(&mut self, pc: u32, mem: &memory::Memory) -> bool {
+        let i = match self.holding(pc, mem) {
+            Some(i) => i,
+            None => return false,
+        };
+        self.list[i].hits += 1;
+        true
+    }
+
+    // the same without counting a hit
+    pub fn holds(&self, pc: u32, mem: &memory::Memory) -> bool {
+        self.holding(pc, mem).is_some()
+    }
+
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.u32(self.list.len() as u32);
+        for breakpoint in self.list.iter() {
+            w.u32(breakpoint.address);
+            w.bool(breakpoint.enabled);
+            w.u32(breakpoint.hits);
+            w.string(&breakpoint.condition);
+        }
+    }
+
+    pub fn load(r: &mut snapshot::Reader) -> io::Result<Breakpoints> {
+        let mut breakpoints = Breakpoints::new();
+        for _ in 0..r.u32()? {
+            let address = r.u32()?;
+            let enabled = r.bool()?;
+            let hits = r.u32()?;
+            let condition = r.string()?;
+            breakpoints.set_condition(address, &condition).map_err(|why| snapshot::invalid(&why))?;
+            if let Ok(i) = breakpoints.find(address) {
+                breakpoints.list[i].enabled = enabled;
+                breakpoints.list[i].hits = hits;
+            }
+        }
+        Ok(breakpoints)
+    }
+
+    // *** private functions *** //
+
+    fn find(&self, address: u32) -> Result<usize, usize> {
+        self.list.binary_search_by_key(&address, |b| b.address)
+    }
+
+    // the enabled breakpoint at pc whose condition holds
+    fn holding(&self, pc: u32, mem: &memory::Memory) -> Option<usize> {
+        let i = self.find(pc).ok()?;
+        let breakpoint = &self.list[i];
+        if !breakpoint.enabled {
+            return None;
+        }
+        if let Some(ref condition) = breakpoint.parsed {
+            if !condition.eval(mem) {
+                return None;
+            }
+        }
+        Some(i)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    Value(u32),
+    Int(u32),  // [address], the word stored there
+    Byte(u32), // b[address]
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, PartialEq)]
+struct Condition {
+    left: Operand,
+    compare: Compare,
+    right: Operand, // without a comparison, the condition is "left != 0"
+}
+
+impl Condition {
+    // the reads aren't logged or watched, they aren't the program's
+    fn eval(&self, mem: &memory::Memory) -> bool {
+        let left = self.left.peek(mem);
+        let right = self.right.peek(mem);
+        match self.compare {
+            Compare::Eq => left == right,
+            Compare::Ne => left != right,
+            Compare::Lt => left < right,
+            Compare::Le => left <= right,
+            Compare::Gt => left > right,
+            Compare::Ge => left >= right,
+        }
+    }
+}
+
+impl Operand {
+    // without logging or watching the read
+    fn peek(&self, mem: &memory::Memory) -> u32 {
+        match *self {
+            Operand::Value(v) => v,
+            Operand::Int(adr) => mem.peek_int_le(adr),
+            Operand::Byte(adr) => mem.peek_byte(adr) as u32,
+        }
+    }
+}
+
+// the value of one side of a condition, like "[reg0]" or "pc", for debuggers to show
+pub fn evaluate(text: &str, mem: &memory::Memory) -> Result<u32, String> {
+    Ok(parse_operand(text)?.peek(mem))
+}
+
+// label names in an expression or condition become their addresses, the register names
+// and the b of b[] stay
+pub fn resolve_labels(text: &str, labels: &[cpu::Label]) -> String {
+    let mut resolved = String::with_capacity(text.len());
+    let mut word = String::new();
+    for c in text.chars().chain(std::iter::once(' ')) {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        let keep = is_register(&word) || (word == "b" && c == '[');
+        match labels.iter().find(|l| l.tag == word) {
+            Some(label) if !keep => resolved.push_str(&format!("0x{:X}", label.address)),
+            _ => resolved.push_str(&word),
+        }
+        word.clear();
+        resolved.push(c);
+    }
+    resolved.pop();
+    resolved
+}
+
+// None when the condition is empty
+fn parse_condition(text: &str) -> Result<Option<Condition>, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    // the two character operators go first, so "<=" isn't taken for "<"
+    let operators = [("==", Compare::Eq), ("!=", Compare::Ne), ("<=", Compare::Le),
+                     (">=", Compare::Ge), ("<", Compare::Lt), (">", Compare::Gt)];
+    for (op, compare) in operators.iter() {
+        if let Some(pos) = text.find(op) {
+            return Ok(Some(Condition {
+                left: parse_operand(&text[..pos])?,
+                compare: *compare,
+                right: parse_operand(&text[pos + op.len()..])?,
+            }));
+        }
+    }
+    Ok(Some(Condition {
+        left: parse_operand(text)?,
+        compare: Compare::Ne,
+        right: Operand::Value(0),
+    }))
+}
+
+// a number (decimal or 0x hex), pc, stack, regN, or one of those in [] or b[] to read memory
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    let text = text.trim();
+    let lower = text.to_lowercase();
+    if lower.starts_with("b[") && lower.ends_with(']') {
+        return Ok(Operand::Byte(parse_address(&lower[2..lower.len() - 1])?));
+    }
+    if lower.starts_with('[') && lower.ends_with(']') {
+        return Ok(Operand::Int(parse_address(&lower[1..lower.len() - 1])?));
+    }
+    Ok(Operand::Value(parse_address(&lower)?))
+}
+
+fn is_register(word: &str) -> bool {
+    word == "pc" || word == "stack" || word.strip_prefix("reg").map_or(false, |n| n.parse::<u32>().is_ok())
+}
+
+fn parse_address(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    let error = || format!("don't know '{}'", text);
+    if text == "pc" {
+        Ok(PC_REG)
+    } else if text == "stack" {
+        Ok(PC_REG + 4)
+    } else if let Some(n) = text.strip_prefix("reg") {
+        let n: u32 = n.parse().map_err(|_| error())?;
+        n.checked_mul(4).and_then(|offset| (PC_REG + 8).checked_add(offset)).ok_or_else(error)
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).map_err(|_| error())
+    } else {
+        text.parse().map_err(|_| error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(left: Operand, compare: Compare, right: Operand) -> Result<Option<Condition>, String> {
+        Ok(Some(Condition { left, compare, right }))
+    }
+
+    #[test]
+    fn parses_conditions() {
+        assert!(parse_condition("  ") == Ok(None));
+        assert!(parse_condition("[reg0] == 5") == condition(Operand::Int(PC_REG + 8), Compare::Eq, Operand::Value(5)));
+        assert!(parse_condition("b[0xE010] != 0") == condition(Operand::Byte(0xE010), Compare::Ne, Operand::Value(0)));
+        assert!(parse_condition("[stack]>0x10") == condition(Operand::Int(PC_REG + 4), Compare::Gt, Operand::Value(0x10)));
+        assert!(parse_condition("B[PC] < 3") == condition(Operand::Byte(PC_REG), Compare::Lt, Operand::Value(3)));
+        // without a comparison it holds when the value isn't 0
+        assert!(parse_condition("[reg15]") == condition(Operand::Int(PC_REG + 68), Compare::Ne, Operand::Value(0)));
+    }
+
+    #[test]
+    fn takes_the_longest_operator() {
+        assert!(parse_condition("[reg1] <= 3") == condition(Operand::Int(PC_REG + 12), Compare::Le, Operand::Value(3)));
+        assert!(parse_condition("[reg1] >= 3") == condition(Operand::Int(PC_REG + 12), Compare::Ge, Operand::Value(3)));
+        // == goes before the single character ones, so a < on its left is part of the operand
+        assert!(parse_condition("1 < 2 == 3").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        for text in ["[reg0] ==", "== 1", "counter", "[0xZZ] == 1", "b[1 == 2", "[reg0x] != 1", "reg99999999999 == 0"].iter() {
+            assert!(parse_condition(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn evaluates_without_logging() {
+        let memory = memory::Memory::new_shared();
+        memory.borrow_mut().write_int_le(0xE000, 7);
+        memory.borrow_mut().set_read_logging(true);
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.set_condition(0x10, "[0xE000] == 7").unwrap();
+        breakpoints.set_condition(0x20, "b[0xE000] > 7").unwrap();
+        assert!(breakpoints.check(0x10, &memory.borrow()));
+        assert!(!breakpoints.check(0x20, &memory.borrow()));
+        assert!(memory.borrow().reads().is_empty());
+        assert_eq!(breakpoints.get(0x10).map(|b| b.hits), Some(1));
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let labels = vec![cpu::Label { address: 0xE000, size: 4, tag: "counter".into() },
+                          cpu::Label { address: 0x10, size: 1, tag: "b".into() }];
+        assert_eq!(resolve_labels("[counter] == 3", &labels), "[0xE000] == 3");
+        assert_eq!(resolve_labels("b[b] != [reg0]", &labels), "b[0x10] != [reg0]");
+    }
+}
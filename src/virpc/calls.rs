@@ -0,0 +1,66 @@
+// the calls that haven't returned yet, for step over and out and for the profiler's call tree.
+// the cpu has no stack of its own: a call returns when execution gets to the position its CLL
+// stored (or the pc an interrupt was taken at), which also returns the calls made after it that
+// didn't return the regular way
+pub const MAX_DEPTH: usize = 256; // deeper calls aren't kept
+
+#[derive(Clone, Copy)]
+pub struct Frame<T> {
+    pub ret: u32, // where execution continues in the caller
+    pub info: T,
+}
+
+#[derive(Clone)]
+pub struct CallStack<T> {
+    frames: Vec<Frame<T>>,
+}
+
+impl<T> Default for CallStack<T> {
+    fn default() -> CallStack<T> {
+        CallStack { frames: Vec::new() }
+    }
+}
+
+impl<T> CallStack<T> {
+    pub fn new() -> CallStack<T> {
+        CallStack::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.frames.len() >= MAX_DEPTH
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // the innermost call
+    pub fn last(&self) -> Option<&Frame<T>> {
+        self.frames.last()
+    }
+
+    // a call that started, it isn't kept when the stack is full
+    pub fn push(&mut self, ret: u32, info: T) {
+        if !self.is_full() {
+            self.frames.push(Frame { ret, info });
+        }
+    }
+
+    // execution got to pc, take off the calls that returned there, the outermost one first
+    pub fn returned(&mut self, pc: u32) -> std::vec::Drain<'_, Frame<T>> {
+        let depth = self.frames.iter().rposition(|f| f.ret == pc).unwrap_or(self.frames.len());
+        self.frames.drain(depth..)
+    }
+
+    pub fn truncate(&mut self, depth: usize) {
+        self.frames.truncate(depth);
+    }
+}
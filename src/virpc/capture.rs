@@ -0,0 +1,136 @@
+// screenshots and frame recording of the window buffer, as PNG or PPM
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+// save a 0x00RRGGBB buffer, the format follows from the extension (.png, anything else is PPM)
+pub fn save_image(filename: &str, buffer: &[u32], width: usize, height: usize) -> io::Result<()> {
+    let data = if filename.to_lowercase().ends_with(".png") {
+        encode_png(buffer, width, height)
+    } else {
+        encode_ppm(buffer, width, height)
+    };
+
+    let mut file = File::create(filename)?;
+    file.write_all(&data)
+}
+
+// writes every nth frame as a numbered image: prefix00000.png, prefix00001.png, ...
+pub struct Recorder {
+    prefix: String,
+    extension: String,
+    every: u32,
+    frame: u32,  // frames since the last saved one
+    saved: u32,  // images written
+}
+
+impl Recorder {
+    pub fn new(prefix: &str, every: u32) -> Recorder {
+        // an extension on the prefix picks the format, png by default
+        let lower = prefix.to_lowercase();
+        let (prefix, extension) = if lower.ends_with(".png") || lower.ends_with(".ppm") {
+            prefix.split_at(prefix.len() - 4)
+        } else {
+            (prefix, ".png")
+        };
+        Recorder {
+            prefix: String::from(prefix),
+            extension: String::from(extension),
+            every: if every == 0 { 1 } else { every },
+            frame: 0,
+            saved: 0,
+        }
+    }
+
+    pub fn frame(&mut self, buffer: &[u32], width: usize, height: usize) -> io::Result<()> {
+        let due = self.frame == 0;
+        self.frame = (self.frame + 1) % self.every;
+        if !due {
+            return Ok(());
+        }
+        let filename = format!("{}{:05}{}", self.prefix, self.saved, self.extension);
+        self.saved += 1;
+        save_image(&filename, buffer, width, height)
+    }
+
+    pub fn saved(&self) -> u32 {
+        self.saved
+    }
+}
+
+// *** private functions *** //
+
+fn encode_ppm(buffer: &[u32], width: usize, height: usize) -> Vec<u8> {
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for pixel in &buffer[..width * height] {
+        data.push((pixel >> 16) as u8);
+        data.push((pixel >> 8) as u8);
+        data.push(*pixel as u8);
+    }
+    data
+}
+
+// uncompressed PNG, the pixel data goes in stored deflate blocks
+fn encode_png(buffer: &[u32], width: usize, height: usize) -> Vec<u8> {
+    let mut raw = Vec::<u8>::with_capacity((width * 3 + 1) * height);
+    for row in buffer[..width * height].chunks(width) {
+        raw.push(0); // no filter
+        for pixel in row {
+            raw.push((pixel >> 16) as u8);
+            raw.push((pixel >> 8) as u8);
+            raw.push(*pixel as u8);
+        }
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xFFFF).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push(if i == blocks.len() - 1 { 1 } else { 0 });
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::<u8>::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits per channel, RGB
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
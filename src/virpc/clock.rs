@@ -29,4 +29,20 @@ impl Clock {
 
         false
     }
+
+    // number of clock periods that passed since the last call, at most max_periods so a
+    // stalled host slows the emulation down instead of trying to catch up all at once
+    pub fn periods_due(&mut self, max_periods: u32) -> u32 {
+        self.curr_time = time::precise_time_s();
+
+        let due = ((self.curr_time - self.last_time) / self.clock_period) as u32;
+        if due > max_periods {
+            self.last_time = self.curr_time;
+            return max_periods
+        }
+
+        // keep the part of a period that has not passed yet
+        self.last_time += due as f64 * self.clock_period;
+        due
+    }
 }
\ No newline at end of file
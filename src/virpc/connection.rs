@@ -0,0 +1,101 @@
+// a single client on a localhost TCP port that never blocks the machine thread, shared by the
+// gdb stub and the debug adapter. what came in waits in input until the protocol takes it
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+
+pub struct Connection {
+    listener: TcpListener,
+    client: Option<TcpStream>, // one client at a time
+    pub input: Vec<u8>,
+    max_input: usize, // a message that never ends can't grow the input past it
+}
+
+impl Connection {
+    // listen on localhost only, the client can read and write all of the machine
+    pub fn bind(port: u16, max_input: usize) -> io::Result<Connection> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(Connection {
+            listener,
+            client: None,
+            input: Vec::new(),
+            max_input,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    // true when a new client connected
+    pub fn accept(&mut self) -> bool {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) => return false,
+        };
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let _ = stream.set_nodelay(true);
+        self.client = Some(stream);
+        self.input.clear();
+        true
+    }
+
+    // false when the connection is closed
+    pub fn receive(&mut self) -> bool {
+        let client = match self.client {
+            Some(ref mut client) => client,
+            None => return false,
+        };
+        let mut buffer = [0u8; 4096];
+        loop {
+            match client.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    if self.input.len() + n > self.max_input {
+                        self.input.clear();
+                    }
+                    self.input.extend_from_slice(&buffer[..n]);
+                }
+                Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref why) if why.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return false,
+            }
+        }
+    }
+
+    // false when the connection is closed, or there was none
+    pub fn write(&mut self, bytes: &[u8]) -> bool {
+        let failed = match self.client {
+            Some(ref mut client) => write_all(client, bytes).is_err(),
+            None => return false,
+        };
+        if failed {
+            self.close();
+        }
+        !failed
+    }
+
+    pub fn close(&mut self) {
+        self.client = None;
+        self.input.clear();
+    }
+}
+
+// the stream doesn't block, so a full send buffer is waited out here
+fn write_all(client: &mut TcpStream, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        match client.write(bytes) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+            Ok(n) => bytes = &bytes[n..],
+            Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            Err(ref why) if why.kind() == io::ErrorKind::Interrupted => (),
+            Err(why) => return Err(why),
+        }
+    }
+    Ok(())
+}
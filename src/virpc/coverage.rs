@@ -0,0 +1,117 @@
+// code coverage: which instructions ran, and for every conditional JMP how often it jumped
+// and how often it fell through. collected by the cpu, the report disassembles the code area
+use std::collections::HashMap;
+use crate::virpc::cpu;
+use crate::virpc::memory;
+use crate::virpc::opcodes;
+
+// outcomes of a conditional jump
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl Branch {
+    // both directions ran
+    pub fn is_covered(&self) -> bool {
+        self.taken > 0 && self.not_taken > 0
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Coverage {
+    executed: HashMap<u32, u64>, // executions per instruction address
+    branches: HashMap<u32, Branch>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub fn record(&mut self, pc: u32) {
+        *self.executed.entry(pc).or_insert(0) += 1;
+    }
+
+    pub fn record_branch(&mut self, pc: u32, taken: bool) {
+        let branch = self.branches.entry(pc).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+
+    pub fn executions(&self, address: u32) -> u64 {
+        self.executed.get(&address).copied().unwrap_or(0)
+    }
+
+    // None for instructions that aren't conditional jumps, or never ran
+    pub fn branch(&self, address: u32) -> Option<Branch> {
+        self.branches.get(&address).copied()
+    }
+
+    // every instruction of the code area up to its last non-zero byte, with a + when it ran (!
+    // for a conditional jump that went one way only) and the labels of the editor
+    pub fn report(&self, mem: &memory::Memory, labels: &[cpu::Label]) -> String {
+        // the disassembler keeps its pc to itself, on a copy of the code area
+        let code = memory::Memory::new_shared();
+        code.borrow_mut().write_block(0, mem.slice(0, cpu::BSS as usize));
+        let disassembler = cpu::CPU::new_shared(0);
+        disassembler.borrow_mut().set_references(code);
+        let mut disassembler = disassembler.borrow_mut();
+        disassembler.labels = labels.to_vec();
+
+        let end = match mem.slice(0, cpu::BSS as usize).iter().rposition(|&b| b != 0) {
+            Some(last) => last as u32 + 1,
+            None => 0,
+        };
+        let mut lines = Vec::new();
+        let (mut instructions, mut covered) = (0, 0);
+        let (mut directions, mut covered_directions) = (0, 0);
+        let mut pc = 0;
+        while pc < end {
+            let next = disassembler.disassemble(pc);
+            let text = disassembler.instruction_to_text();
+            let text = text.trim_end();
+            let executions = self.executions(pc);
+            instructions += 1;
+            if executions > 0 {
+                covered += 1;
+            }
+
+            let mut marker = if executions > 0 { '+' } else { '-' };
+            let mut outcome = String::new();
+            if is_conditional_jump(&disassembler) {
+                let branch = self.branch(pc).unwrap_or_default();
+                directions += 2;
+                covered_directions += (branch.taken > 0) as u32 + (branch.not_taken > 0) as u32;
+                if executions > 0 && !branch.is_covered() {
+                    marker = '!';
+                }
+                outcome = format!("  taken {}, not taken {}", branch.taken, branch.not_taken);
+            }
+            lines.push(format!("{} {:>8} {}{}", marker, executions, text, outcome));
+            pc = next;
+        }
+
+        let percentage = |part: u32, whole: u32| if whole == 0 { 100.0 } else { part as f64 * 100.0 / whole as f64 };
+        let mut report = format!("instructions: {} of {} ({:.1}%)\nbranch directions: {} of {} ({:.1}%)\n\n",
+            covered, instructions, percentage(covered, instructions),
+            covered_directions, directions, percentage(covered_directions, directions));
+        for line in lines {
+            report.push_str(&line);
+            report.push('\n');
+        }
+        report
+    }
+}
+
+// a JMP with a condition (1-16) in its second operand, the others always jump
+pub fn is_conditional_jump(cpu: &cpu::CPU) -> bool {
+    match cpu.instruction.opcode {
+        opcodes::Op::JMP => (1..=16).contains(&cpu.instruction.arg[1]),
+        _ => false,
+    }
+}
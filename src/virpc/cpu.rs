@@ -1,9 +1,12 @@
 //#![allow(non_snake_case)]
 // The CPU
+use crate::virpc::coverage;
 use crate::virpc::memory;
 use crate::virpc::opcodes;
+use crate::virpc::snapshot;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::io;
 use ncurses::*;
 
 use opcodes::ArgumentSize;
@@ -28,6 +31,14 @@ pub const STACK_REG: u32 = 0xF004;
 //pub const B_REG: u32 = 0xF00B;
 //pub const C_REG: u32 = 0xF010;
 
+// interrupt controller registers
+pub const IRQ_VECTOR: u32 = 0xF200;  // address of the interrupt handler (int)
+pub const IRQ_RETURN: u32 = 0xF204;  // pc at the moment the interrupt was taken (int)
+pub const IRQ_ENABLE: u32 = 0xF208;  // enable mask, one bit per Interrupt source (byte)
+pub const IRQ_STATUS: u32 = 0xF209;  // latched pending sources, write 0 to acknowledge (byte)
+pub const IRQ_CONTROL: u32 = 0xF20A; // bit 0: master enable, cleared on entering the handler (byte)
+pub const IRQ_FLAGS: u32 = 0xF20B;   // status flags at the moment the interrupt was taken (byte)
+
 // status flags for P register
 pub enum StatusFlag {
     Carry            = 1 << 0,
@@ -37,6 +48,14 @@ pub enum StatusFlag {
     Negative         = 1 << 7,
 }
 
+// interrupt sources, as bits in IRQ_ENABLE and IRQ_STATUS
+#[derive(Clone, Copy)]
+pub enum Interrupt {
+    Dma              = 1 << 0,
+    Sprite           = 1 << 1,
+    Raster           = 1 << 2,
+}
+
 pub enum CPUState {
     FetchOp,
     FetchOperandAddr,
@@ -49,7 +68,31 @@ pub struct Items {
     value : u32,
 }
 
-#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+// an instruction in the code area as decoded the first time it ran. Reference operands
+// keep their address, the value behind it is read every time the instruction runs
+#[derive(Clone, Copy)]
+struct Decoded {
+    versions: [u32; 2], // of the code pages of its first and last byte when decoded, 0 for an empty entry
+    instruction_u8: u8,
+    operands: [u32; 3],
+    ends: [u32; 3], // length of the instruction up to and including each operand
+    length: u32,
+}
+
+// bytes of an instruction with its first size operands: references and int operands take 4, byte operands 1
+fn instruction_length(size: u8, arguments: u8, addr_type: &ArgumentSize) -> u32 {
+    let mut length = 1;
+    for arg_i in 0..size {
+        let reference = (arguments << arg_i) & 0x04 > 0;
+        length += match addr_type {
+            ArgumentSize::Byte if !reference => 1,
+            _ => 4,
+        };
+    }
+    length
+}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Label {
     pub address : u32,
     pub size : u32,
@@ -68,6 +111,12 @@ pub struct CPU {
     pub labels : Vec<Label>,
     pub pc_reg : u32,
     pc : u32,
+    irq_armed : bool,
+    interrupted : bool, // the last update entered the interrupt handler before its instruction
+    cache_enabled : bool,
+    cache : Vec<Decoded>, // one entry per address in the code area, allocated on first use
+    covering : bool,
+    coverage : Option<coverage::Coverage>, // kept after covering stopped, until it is cleared
 }
 
 impl CPU {
@@ -83,6 +132,12 @@ impl CPU {
             labels : Vec::new(),
             pc_reg : pc,
             pc : 0,
+            irq_armed : false,
+            interrupted : false,
+            cache_enabled : true,
+            cache : Vec::new(),
+            covering : false,
+            coverage : None,
         }))
     }
 
@@ -95,7 +150,7 @@ impl CPU {
             self.pc = lpc;
         }
         else {
-            as_ref!(self.mem_ref).write_int_le(self.pc_reg,lpc);            
+            as_ref!(self.mem_ref).poke_int_le(self.pc_reg,lpc);            
         }
     }
 
@@ -104,7 +159,7 @@ impl CPU {
             self.pc
         }
         else {
-            as_ref!(self.mem_ref).read_int_le(self.pc_reg)
+            as_ref!(self.mem_ref).peek_int_le(self.pc_reg)
         }
     }
     
@@ -133,11 +188,96 @@ impl CPU {
         self.instruction_u8 = 0;
         self.state = CPUState::FetchOp;
         self.prev_pc = 0;
+        self.irq_armed = false;
         // I'm only doing this to avoid dead code warning :)
         self.set_status_flag(StatusFlag::Unused, false);
     }
 
+    // the state that isn't in memory, for snapshots
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.u8(self.p);
+        w.u8(match self.state {
+            CPUState::FetchOp => 0,
+            CPUState::FetchOperandAddr => 1,
+            CPUState::ExecuteOp => 2,
+        });
+        w.u8(self.instruction_u8);
+        w.u32(self.prev_pc);
+        w.u32(self.pc);
+        w.bool(self.irq_armed);
+    }
+
+    // all or nothing, a short section leaves the cpu as it was
+    pub fn load(&mut self, r: &mut snapshot::Reader) -> io::Result<()> {
+        let p = r.u8()?;
+        let state = match r.u8()? {
+            0 => CPUState::FetchOp,
+            1 => CPUState::FetchOperandAddr,
+            _ => CPUState::ExecuteOp,
+        };
+        let instruction_u8 = r.u8()?;
+        let prev_pc = r.u32()?;
+        let pc = r.u32()?;
+        let irq_armed = r.bool()?;
+        self.p = p;
+        self.state = state;
+        self.instruction_u8 = instruction_u8;
+        self.prev_pc = prev_pc;
+        self.pc = pc;
+        self.irq_armed = irq_armed;
+        Ok(())
+    }
+
+    // interrupt state that isn't in memory, for the journal to restore
+    pub fn is_irq_armed(&self) -> bool {
+        self.irq_armed
+    }
+
+    pub fn set_irq_armed(&mut self, armed: bool) {
+        self.irq_armed = armed;
+    }
+
+    // true when the instruction of the last update was the first one of the interrupt handler
+    pub fn entered_interrupt(&self) -> bool {
+        self.interrupted
+    }
+
     pub fn update(&mut self) {
+        self.check_interrupt();
+        if self.covering {
+            let pc = self.get_pc();
+            self.execute();
+            self.covered(pc);
+        } else {
+            self.execute();
+        }
+    }
+
+    // collect which instructions run and which way conditional jumps go, a new coverage starts empty
+    pub fn set_coverage(&mut self, covering: bool) {
+        self.covering = covering;
+        if covering && self.coverage.is_none() {
+            self.coverage = Some(coverage::Coverage::new());
+        }
+    }
+
+    pub fn is_covering(&self) -> bool {
+        self.covering
+    }
+
+    pub fn clear_coverage(&mut self) {
+        self.coverage = if self.covering { Some(coverage::Coverage::new()) } else { None };
+    }
+
+    pub fn coverage(&self) -> Option<&coverage::Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // run the instruction at pc, the interrupt check is done
+    fn execute(&mut self) {
+        if self.cache_enabled && self.run_cached() {
+            return;
+        }
         let next_op = self.next_byte(); //retrieve next byte
         match opcodes::get_instruction(next_op) { //retrieve instruction
             Some((opcode, size, arguments, addr_type)) => {
@@ -160,19 +300,183 @@ impl CPU {
         }
     }
 
+    // the instruction from pc ran, a conditional jump that didn't end up behind itself was taken
+    fn covered(&mut self, pc: u32) {
+        let conditional = coverage::is_conditional_jump(self);
+        let length = instruction_length(self.instruction.size, self.instruction.args, &self.instruction.addressing_type);
+        let taken = conditional && self.get_pc() != pc + length;
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record(pc);
+            if conditional {
+                coverage.record_branch(pc, taken);
+            }
+        }
+    }
+
+    // decode instructions in the code area only once, until the code area is written to
+    pub fn set_cache(&mut self, enabled: bool) {
+        self.cache_enabled = enabled;
+        self.cache.clear();
+    }
+
+    // run the instruction at pc from the cache, decoding it into the cache first if needed.
+    // returns false for instructions outside the code area, which are decoded every time
+    fn run_cached(&mut self) -> bool {
+        let pc = self.get_pc();
+        if pc >= BSS {
+            return false;
+        }
+        if self.cache.is_empty() {
+            self.cache = vec![Decoded { versions: [0; 2], instruction_u8: 0, operands: [0; 3], ends: [0; 3], length: 1 }; BSS as usize];
+        }
+
+        // only writes to the pages under the instruction drop it
+        let mut decoded = self.cache[pc as usize];
+        let current = {
+            let mem = as_ref!(self.mem_ref);
+            let last = pc + decoded.length - 1;
+            decoded.versions[0] == mem.code_page_version(pc)
+                && (last / memory::CODE_PAGE == pc / memory::CODE_PAGE || decoded.versions[1] == mem.code_page_version(last))
+        };
+        if !current {
+            decoded = self.decode(pc);
+            self.cache[pc as usize] = decoded;
+        }
+
+        match opcodes::get_instruction(decoded.instruction_u8) {
+            Some((opcode, size, arguments, addr_type)) => {
+                self.instruction.opcode = opcode;
+                self.instruction.size = size;
+                self.instruction.args = arguments;
+                self.instruction.addressing_type = addr_type;
+                self.instruction_u8 = decoded.instruction_u8;
+            }
+            None => panic!("Can't fetch instruction")
+        }
+        for arg_i in 0..self.instruction.size as usize {
+            let operand = decoded.operands[arg_i];
+            self.instruction.arg[arg_i] = if (self.instruction.args << arg_i) & 0x04 > 0 {
+                // a reference to the pc register sees it as far as update would have moved it
+                if operand.wrapping_sub(self.pc_reg).wrapping_add(3) < 7 {
+                    self.set_pc(pc + decoded.ends[arg_i]);
+                }
+                match self.instruction.addressing_type {
+                    ArgumentSize::Byte => self.read_byte(operand) as u32,
+                    ArgumentSize::Int => self.read_int_le(operand),
+                }
+            } else {
+                operand
+            };
+        }
+        self.set_pc(pc + decoded.length);
+
+        self.state = CPUState::ExecuteOp;
+        if opcodes::run(self) {
+            self.state = CPUState::FetchOp;
+        }
+        true
+    }
+
+    // read an instruction and its operands the same way update does, without running it
+    fn decode(&mut self, pc: u32) -> Decoded {
+        let mem = as_ref!(self.mem_ref);
+        let instruction_u8 = mem.peek_byte(pc);
+        let mut decoded = Decoded { versions: [0; 2], instruction_u8, operands: [0; 3], ends: [0; 3], length: 1 };
+        if let Some((_, size, arguments, addr_type)) = opcodes::get_instruction(instruction_u8) {
+            for arg_i in 0..size as usize {
+                let addr = pc + decoded.length;
+                let end = instruction_length(arg_i as u8 + 1, arguments, &addr_type);
+                decoded.operands[arg_i] = match end - decoded.length {
+                    1 => mem.peek_byte(addr) as u32,
+                    _ => mem.peek_int_le(addr),
+                };
+                decoded.length = end;
+                decoded.ends[arg_i] = end;
+            }
+        }
+        decoded.versions = [mem.code_page_version(pc), mem.code_page_version(pc + decoded.length - 1)];
+        decoded
+    }
+
+    // the operation and length of the instruction at pc, peeked so watchpoints and traces don't see it
+    pub fn peek_instruction(&self, pc: u32) -> Option<(Op, u32)> {
+        let mem = as_ref!(self.mem_ref);
+        let (op, size, arguments, addr_type) = opcodes::get_instruction(mem.peek_byte(pc))?;
+        Some((op, instruction_length(size, arguments, &addr_type)))
+    }
+
+    // latch an interrupt request from a peripheral
+    pub fn raise_interrupt(&mut self, source: Interrupt) {
+        let status = self.read_byte(IRQ_STATUS);
+        self.write_byte(IRQ_STATUS, status | source as u8);
+    }
+
+    // enter the handler at IRQ_VECTOR if an enabled source is pending. Setting the master
+    // enable only takes effect one instruction later, so a handler can re-enable interrupts
+    // and return with JMP [IRQ_RETURN] without being re-entered in between. the flags are
+    // saved in IRQ_FLAGS, that return restores them
+    // the registers are peeked, they aren't reads of the program for watchpoints and traces
+    fn check_interrupt(&mut self) {
+        let control = as_ref!(self.mem_ref).peek_byte(IRQ_CONTROL);
+        let enabled = control & 0x01 != 0;
+        let armed = enabled && self.irq_armed;
+        self.irq_armed = enabled;
+        self.interrupted = false;
+
+        if armed && self.pending_interrupt() {
+            let pc = self.get_pc();
+            self.write_int_le(IRQ_RETURN, pc);
+            self.write_byte(IRQ_FLAGS, self.p);
+            self.write_byte(IRQ_CONTROL, control & !0x01);
+            self.irq_armed = false;
+            self.interrupted = true;
+            let vector = as_ref!(self.mem_ref).peek_int_le(IRQ_VECTOR);
+            self.set_pc(vector);
+        }
+    }
+
+    // true when the jump that is running reads its target from IRQ_RETURN, pc is behind it
+    pub fn is_interrupt_return(&self) -> bool {
+        let length = instruction_length(self.instruction.size, self.instruction.args, &self.instruction.addressing_type);
+        let operand = self.get_pc().wrapping_sub(length).wrapping_add(1);
+        as_ref!(self.mem_ref).peek_int_le(operand) == IRQ_RETURN
+    }
+
+    // back from the handler, with the flags of the interrupted code
+    pub fn restore_interrupt_flags(&mut self) {
+        self.p = as_ref!(self.mem_ref).peek_byte(IRQ_FLAGS);
+    }
+
+    fn pending_interrupt(&self) -> bool {
+        let mem = as_ref!(self.mem_ref);
+        mem.peek_byte(IRQ_STATUS) & mem.peek_byte(IRQ_ENABLE) != 0
+    }
+
+    // fetches are peeked like the decode cache does, they aren't reads of the program
     pub fn next_byte(&mut self) -> u8 {
-        let mut pc = self.get_pc();
-        let op = self.read_byte(pc);
-        pc += 1;
-        self.set_pc(pc);
+        if self.pc_reg == 0 {
+            let op = as_ref!(self.mem_ref).peek_byte(self.pc);
+            self.pc += 1;
+            return op;
+        }
+        // pc register and operand in a single borrow
+        let mut mem = as_mut!(self.mem_ref);
+        let pc = mem.peek_int_le(self.pc_reg);
+        let op = mem.peek_byte(pc);
+        mem.poke_int_le(self.pc_reg, pc + 1);
         op
     }
 
     pub fn next_int(&mut self) -> u32 {
-        let mut pc = self.get_pc();
-        let op = self.read_int_le(pc);
-        pc += 4;
-        self.set_pc(pc);
+        if self.pc_reg == 0 {
+            let op = as_ref!(self.mem_ref).peek_int_le(self.pc);
+            self.pc += 4;
+            return op;
+        }
+        let mut mem = as_mut!(self.mem_ref);
+        let pc = mem.peek_int_le(self.pc_reg);
+        let op = mem.peek_int_le(pc);
+        mem.poke_int_le(self.pc_reg, pc + 4);
         op
     }
 
@@ -0,0 +1,858 @@
+// debug adapter protocol server on a localhost TCP port, for VS Code and other DAP clients.
+// the machine thread polls it like the gdb stub. the program is shown as a listing of the code
+// area, one instruction per line with the editor's labels, the registers and labels as variables
+use std::io;
+use std::path::Path;
+use crate::utils::json;
+use crate::utils::json::Value;
+use crate::virpc;
+use crate::virpc::breakpoints;
+use crate::virpc::connection::Connection;
+use crate::virpc::cpu;
+use crate::virpc::memory;
+use crate::virpc::PC_REG;
+
+const THREAD: u32 = 1;          // the cpu is the only thread, with a single frame
+const FRAME: u32 = 1;
+const LISTING: u32 = 1;         // source reference of the listing
+const LISTING_NAME: &str = "program.lst";
+const SCOPE_REGISTERS: u32 = 1; // variables references
+const SCOPE_LABELS: u32 = 2;
+const INT_REGISTERS: u32 = 18;  // pc, stack and reg0-reg15 from PC_REG on, the status flags after them
+const MAX_READ: usize = 0x10000;
+const MAX_MESSAGE: usize = 0x100000; // longer messages are dropped
+const MAX_INPUT: usize = MAX_MESSAGE + 0x1000; // a message and its header
+
+// an instruction of the listing
+struct Line {
+    address: u32,
+    length: u32,
+    text: String,        // as in the editor, with address and label
+    instruction: String, // just the instruction
+}
+
+pub struct Server {
+    connection: Connection, // one client at a time
+    seq: i64,
+    configured: bool,       // stops and continues are reported from configurationDone on
+    run_configured: bool,   // a launch without stopOnEntry runs from configurationDone on
+    running: bool,          // as last reported
+    stop_reason: Option<&'static str>, // of the next stop, when the client asked for it
+    source_breakpoints: Vec<u32>,      // set by the client, on the listing
+    instruction_breakpoints: Vec<u32>, // and in the disassembly view
+    data_breakpoints: Vec<u32>,
+    listing: Vec<Line>, // as last sent, to find the address of a line
+    listed: Option<(u32, Vec<cpu::Label>)>, // the code version and labels of the listing
+}
+
+impl Server {
+    pub fn bind(port: u16) -> io::Result<Server> {
+        Ok(Server {
+            connection: Connection::bind(port, MAX_INPUT)?,
+            seq: 0,
+            configured: false,
+            run_configured: false,
+            running: false,
+            stop_reason: None,
+            source_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+            data_breakpoints: Vec::new(),
+            listing: Vec::new(),
+            listed: None,
+        })
+    }
+
+    // accept a connection, answer the requests and report stops and continues, never blocks.
+    // returns true when the client changed the machine
+    pub fn poll(&mut self, virpc: &mut virpc::Virpc) -> bool {
+        if !self.connection.is_connected() && !self.accept() {
+            return false;
+        }
+        if !self.connection.receive() {
+            self.disconnect(virpc);
+            return false;
+        }
+
+        let mut changed = false;
+        while let Some(message) = self.next_message() {
+            changed = true;
+            if let Ok(request) = Value::parse(&message) {
+                self.handle(&request, virpc);
+            }
+            if !self.connection.is_connected() {
+                return changed;
+            }
+        }
+        self.report(virpc);
+        changed
+    }
+
+    // *** private functions *** //
+
+    fn accept(&mut self) -> bool {
+        if !self.connection.accept() {
+            return false;
+        }
+        self.configured = false;
+        self.run_configured = false;
+        self.stop_reason = None;
+        true
+    }
+
+    // the breakpoints and watchpoints of the client go with it, the machine keeps running
+    fn disconnect(&mut self, virpc: &mut virpc::Virpc) {
+        for address in self.source_breakpoints.drain(..).chain(self.instruction_breakpoints.drain(..)) {
+            virpc.breakpoints().remove(address);
+        }
+        for address in self.data_breakpoints.drain(..) {
+            virpc.memory.borrow_mut().unwatch(address);
+        }
+        self.connection.close();
+        self.configured = false;
+    }
+
+    // the body of the next complete message, after its Content-Length header
+    fn next_message(&mut self) -> Option<String> {
+        let end = self.connection.input.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let header = String::from_utf8_lossy(&self.connection.input[..end]).into_owned();
+        let length = header.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+        let length = match length {
+            Some(length) if length <= MAX_MESSAGE => length,
+            Some(_) => {
+                // too long to take, the rest can't be told apart from its body
+                self.connection.input.clear();
+                return None;
+            }
+            None => {
+                // not a message, drop the header
+                self.connection.input.drain(..end + 4);
+                return None;
+            }
+        };
+        let total = (end + 4).checked_add(length)?;
+        if self.connection.input.len() < total {
+            return None;
+        }
+        let body = String::from_utf8_lossy(&self.connection.input[end + 4..total]).into_owned();
+        self.connection.input.drain(..total);
+        Some(body)
+    }
+
+    fn handle(&mut self, request: &Value, virpc: &mut virpc::Virpc) {
+        let command = request.get("command").as_str().unwrap_or("").to_string();
+        let args = request.get("arguments");
+        let result = match command.as_str() {
+            "initialize" => Ok(capabilities()),
+            "launch" | "attach" => self.launch(args, virpc, command == "launch"),
+            "configurationDone" => {
+                self.configured = true;
+                self.running = true; // the client takes the machine for running
+                if self.run_configured {
+                    virpc.continue_cpu();
+                } else if !virpc.status() && self.stop_reason.is_none() {
+                    self.stop_reason = Some("entry");
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json::object(vec![
+                ("threads", vec![json::object(vec![("id", THREAD.into()), ("name", "VirPC".into())])].into()),
+            ])),
+            "stackTrace" => Ok(self.stack_trace(virpc)),
+            "source" => {
+                self.update_listing(virpc);
+                let lines: Vec<&str> = self.listing.iter().map(|l| l.text.as_str()).collect();
+                Ok(json::object(vec![("content", lines.join("\n").into()), ("mimeType", "text/plain".into())]))
+            }
+            "scopes" => Ok(json::object(vec![("scopes", vec![
+                json::object(vec![("name", "Registers".into()), ("presentationHint", "registers".into()),
+                                  ("variablesReference", SCOPE_REGISTERS.into()), ("expensive", false.into())]),
+                json::object(vec![("name", "Labels".into()),
+                                  ("variablesReference", SCOPE_LABELS.into()), ("expensive", false.into())]),
+            ].into())])),
+            "variables" => Ok(variables(args, virpc)),
+            "setVariable" => set_variable(args, virpc),
+            "evaluate" => evaluate(args, virpc),
+            "continue" => {
+                virpc.continue_cpu();
+                self.running = true;
+                Ok(json::object(vec![("allThreadsContinued", true.into())]))
+            }
+            "next" => {
+                virpc.step_over();
+                self.stepping();
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                virpc.step();
+                self.stepping();
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                if virpc.step_out() {
+                    self.stepping();
+                    Ok(Value::Null)
+                } else {
+                    Err("not in a call".to_string())
+                }
+            }
+            "pause" => {
+                if virpc.status() {
+                    virpc.stop();
+                    self.stop_reason = Some("pause");
+                }
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => Ok(self.set_breakpoints(args, virpc)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args, virpc)),
+            "setExceptionBreakpoints" => Ok(json::object(vec![("breakpoints", Value::Array(Vec::new()))])),
+            "dataBreakpointInfo" => Ok(data_breakpoint_info(args, virpc)),
+            "setDataBreakpoints" => Ok(self.set_data_breakpoints(args, virpc)),
+            "disassemble" => Ok(self.disassemble(args, virpc)),
+            "readMemory" => read_memory(args, virpc),
+            "writeMemory" => write_memory(args, virpc),
+            "disconnect" => {
+                // stopping a launched program stops the machine, otherwise it keeps going
+                if args.get("terminateDebuggee").as_bool().unwrap_or(false) {
+                    virpc.stop();
+                } else {
+                    virpc.continue_cpu();
+                }
+                Ok(Value::Null)
+            }
+            _ => Err(format!("{} isn't supported", command)),
+        };
+        let request_seq = request.get("seq").as_i64().unwrap_or(0);
+        self.respond(request_seq, &command, result);
+
+        match command.as_str() {
+            "initialize" => self.event("initialized", Value::Null),
+            "disconnect" => self.disconnect(virpc),
+            _ => (),
+        }
+    }
+
+    // a launch can load a program, it runs once the client is configured unless it stops on entry
+    fn launch(&mut self, args: &Value, virpc: &mut virpc::Virpc, launch: bool) -> Result<Value, String> {
+        let stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        if let Some(program) = args.get("program").as_str() {
+            let size = Path::new(program).metadata().map(|m| if m.is_file() { m.len() } else { 0 }).unwrap_or(0);
+            if size < 2 {
+                return Err(format!("{} isn't a program", program));
+            }
+            virpc.stop();
+            virpc.reset();
+            // reset leaves RAM alone, nothing of the previous program may stay in the code area
+            virpc.memory.borrow_mut().write_block(0, &vec![0u8; cpu::BSS as usize]);
+            virpc::load_prg(&virpc.memory, program);
+        }
+        if stop_on_entry {
+            virpc.stop();
+            self.stop_reason = Some("entry");
+        }
+        self.run_configured = launch && !stop_on_entry;
+        Ok(Value::Null)
+    }
+
+    // disassembling the code area takes a while, so it is done again only after the code or the
+    // labels changed, or when the pc went past the end of the listing
+    fn update_listing(&mut self, virpc: &virpc::Virpc) {
+        let version = virpc.memory.borrow().code_version();
+        let pc = virpc.cpu.borrow().get_pc();
+        let end = self.listing.last().map_or(0, |l| l.address + l.length);
+        let current = match self.listed {
+            Some((listed, ref labels)) => listed == version && labels.as_slice() == virpc.labels(),
+            None => false,
+        };
+        if !current || (pc < cpu::BSS && pc >= end) {
+            self.listing = listing(virpc);
+            self.listed = Some((version, virpc.labels().to_vec()));
+        }
+    }
+
+    // the client doesn't expect a continued event for a step, only the stop. a step over a call
+    // or out of one still runs when the request is answered
+    fn stepping(&mut self) {
+        self.running = true;
+        self.stop_reason = Some("step");
+    }
+
+    // tell the client when the machine stopped or continued, also when the editor did it
+    fn report(&mut self, virpc: &mut virpc::Virpc) {
+        let running = virpc.status();
+        if !self.configured || running == self.running {
+            return;
+        }
+        self.running = running;
+        if running {
+            self.stop_reason = None;
+            self.event("continued", json::object(vec![("threadId", THREAD.into()), ("allThreadsContinued", true.into())]));
+            return;
+        }
+
+        let mut body = vec![("threadId", THREAD.into()), ("allThreadsStopped", true.into())];
+        let pc = virpc.cpu.borrow().get_pc();
+        let at_breakpoint = virpc.breakpoints().get(pc).is_some();
+        let reason = match (self.stop_reason.take(), virpc.watch_hit()) {
+            // a step can end early on a watchpoint or breakpoint
+            (_, Some(hit)) => {
+                let description = if hit.watch == memory::Watch::Read {
+                    format!("read of 0x{:08X}", hit.address)
+                } else {
+                    format!("write to 0x{:08X}, {} -> {}", hit.address, hit.old, hit.new)
+                };
+                body.push(("description", description.into()));
+                "data breakpoint"
+            }
+            (Some("step"), None) if at_breakpoint => "breakpoint",
+            (Some(reason), None) => reason,
+            (None, None) => if at_breakpoint { "breakpoint" } else { "pause" },
+        };
+        body.push(("reason", reason.into()));
+        self.event("stopped", json::object(body));
+    }
+
+    fn stack_trace(&mut self, virpc: &mut virpc::Virpc) -> Value {
+        self.update_listing(virpc);
+        let pc = virpc.cpu.borrow().get_pc();
+        let mut frame = vec![
+            ("id", FRAME.into()),
+            ("name", function_name(pc, virpc.labels()).into()),
+            ("instructionPointerReference", reference(pc).into()),
+        ];
+        match self.listing.iter().position(|l| l.address == pc) {
+            Some(i) => {
+                frame.push(("line", (i + 1).into()));
+                frame.push(("column", 1u32.into()));
+                frame.push(("source", listing_source()));
+            }
+            // outside the code area there is only the disassembly
+            None => {
+                frame.push(("line", 0u32.into()));
+                frame.push(("column", 0u32.into()));
+            }
+        }
+        json::object(vec![("stackFrames", vec![json::object(frame)].into()), ("totalFrames", 1u32.into())])
+    }
+
+    // breakpoints on lines of the listing, the others don't have code
+    fn set_breakpoints(&mut self, args: &Value, virpc: &mut virpc::Virpc) -> Value {
+        let source = args.get("source");
+        let ours = source.get("sourceReference").as_i64() == Some(LISTING as i64)
+            || source.get("name").as_str() == Some(LISTING_NAME);
+        self.update_listing(virpc);
+
+        let mut wanted = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let line = breakpoint.get("line").as_i64().unwrap_or(0);
+            let address = match line.checked_sub(1).and_then(|i| self.listing.get(i as usize)) {
+                Some(l) if ours => Ok(l.address),
+                _ if ours => Err("there is no instruction on this line".to_string()),
+                _ => Err(format!("only {} has breakpoints", LISTING_NAME)),
+            };
+            wanted.push((address, condition(breakpoint, virpc.labels()), Some(line)));
+        }
+        let old = std::mem::take(&mut self.source_breakpoints);
+        let (placed, breakpoints) = place_breakpoints(virpc, &old, &self.instruction_breakpoints, wanted);
+        self.source_breakpoints = placed;
+        json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    // breakpoints set in the disassembly view
+    fn set_instruction_breakpoints(&mut self, args: &Value, virpc: &mut virpc::Virpc) -> Value {
+        let mut wanted = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let address = breakpoint.get("instructionReference").as_str().and_then(parse_reference)
+                .map(|a| a.wrapping_add(breakpoint.get("offset").as_i64().unwrap_or(0) as u32))
+                .ok_or_else(|| "not an address".to_string());
+            wanted.push((address, condition(breakpoint, virpc.labels()), None));
+        }
+        let old = std::mem::take(&mut self.instruction_breakpoints);
+        let (placed, breakpoints) = place_breakpoints(virpc, &old, &self.source_breakpoints, wanted);
+        self.instruction_breakpoints = placed;
+        json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    // watchpoints on the data ids of data_breakpoint_info, replacing the ones set before
+    fn set_data_breakpoints(&mut self, args: &Value, virpc: &mut virpc::Virpc) -> Value {
+        let mut mem = virpc.memory.borrow_mut();
+        for address in self.data_breakpoints.drain(..) {
+            mem.unwatch(address);
+        }
+        let mut breakpoints = Vec::new();
+        for breakpoint in args.get("breakpoints").as_array() {
+            let data = breakpoint.get("dataId").as_str()
+                .and_then(|id| id.split_once('/'))
+                .and_then(|(address, size)| Some((parse_reference(address)?, size.parse::<u32>().ok()?)));
+            let watch = match breakpoint.get("accessType").as_str() {
+                Some("read") => Some(memory::Watch::Read),
+                Some("readWrite") => None,
+                _ => Some(memory::Watch::Write),
+            };
+            let result = match (data, watch) {
+                (Some((address, size)), Some(watch)) => {
+                    mem.watch(address, size, watch);
+                    self.data_breakpoints.push(address);
+                    json::object(vec![("id", address.into()), ("verified", true.into())])
+                }
+                (_, None) => unverified("read and write can't be watched together, add one of each"),
+                (None, _) => unverified("not a data breakpoint of this machine"),
+            };
+            breakpoints.push(result);
+        }
+        json::object(vec![("breakpoints", breakpoints.into())])
+    }
+
+    // instructions around an address, before it with a negative instructionOffset
+    fn disassemble(&mut self, args: &Value, virpc: &mut virpc::Virpc) -> Value {
+        self.update_listing(virpc);
+        let base = args.get("memoryReference").as_str().and_then(parse_reference).unwrap_or(0)
+            .wrapping_add(args.get("offset").as_i64().unwrap_or(0) as u32);
+        let first = args.get("instructionOffset").as_i64().unwrap_or(0);
+        let count = args.get("instructionCount").as_i64().unwrap_or(0).clamp(0, 10000);
+
+        let start = self.listing.iter().position(|l| base < l.address + l.length).unwrap_or(self.listing.len()) as i64;
+        let end = self.listing.last().map_or(0, |l| l.address + l.length);
+        let labels = virpc.labels();
+        let mem = virpc.memory.borrow();
+        let mut instructions = Vec::new();
+        for k in 0..count {
+            let i = start + first + k;
+            let instruction = match self.listing.get(i.max(0) as usize) {
+                Some(line) if i >= 0 => {
+                    let bytes: Vec<String> = mem.slice(line.address, line.length as usize).iter()
+                        .map(|b| format!("{:02X}", b)).collect();
+                    let mut fields = vec![
+                        ("address", reference(line.address).into()),
+                        ("instruction", line.instruction.clone().into()),
+                        ("instructionBytes", bytes.join(" ").into()),
+                        ("line", (i as usize + 1).into()),
+                        ("location", listing_source()),
+                    ];
+                    if let Some(label) = labels.iter().find(|l| l.address == line.address) {
+                        fields.push(("symbol", label.tag.clone().into()));
+                    }
+                    json::object(fields)
+                }
+                // before the code area, or after it
+                _ => {
+                    let address = if i < 0 { 0 } else { end.wrapping_add((i - self.listing.len() as i64) as u32) };
+                    json::object(vec![("address", reference(address).into()), ("instruction", "".into()),
+                                      ("presentationHint", "invalid".into())])
+                }
+            };
+            instructions.push(instruction);
+        }
+        json::object(vec![("instructions", instructions.into())])
+    }
+
+    fn respond(&mut self, request_seq: i64, command: &str, result: Result<Value, String>) {
+        let mut fields = vec![
+            ("type", "response".into()),
+            ("request_seq", request_seq.into()),
+            ("command", command.into()),
+        ];
+        match result {
+            Ok(body) => {
+                fields.push(("success", true.into()));
+                if body != Value::Null {
+                    fields.push(("body", body));
+                }
+            }
+            Err(message) => {
+                fields.push(("success", false.into()));
+                fields.push(("message", message.into()));
+            }
+        }
+        self.send(fields);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        let mut fields = vec![("type", "event".into()), ("event", event.into())];
+        if body != Value::Null {
+            fields.push(("body", body));
+        }
+        self.send(fields);
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Value)>) {
+        self.seq += 1;
+        fields.insert(0, ("seq", self.seq.into()));
+        let body = json::object(fields).to_string();
+        let message = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        self.connection.write(message.as_bytes());
+    }
+}
+
+fn capabilities() -> Value {
+    json::object(vec![
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsConditionalBreakpoints", true.into()),
+        ("supportsInstructionBreakpoints", true.into()),
+        ("supportsDataBreakpoints", true.into()),
+        ("supportsDisassembleRequest", true.into()),
+        ("supportsReadMemoryRequest", true.into()),
+        ("supportsWriteMemoryRequest", true.into()),
+        ("supportsSetVariable", true.into()),
+        ("supportsEvaluateForHovers", true.into()),
+        ("supportsValueFormattingOptions", true.into()),
+        ("supportsSteppingGranularity", true.into()),
+    ])
+}
+
+// every instruction of the code area up to its last non-zero byte (or the pc), disassembled
+// on a copy so the machine's pc, watchpoints and logs aren't touched
+fn listing(virpc: &virpc::Virpc) -> Vec<Line> {
+    let pc = virpc.cpu.borrow().get_pc(); // reads memory, so before it is borrowed here
+    let mem = virpc.memory.borrow();
+    let code = memory::Memory::new_shared();
+    code.borrow_mut().write_block(0, mem.slice(0, cpu::BSS as usize));
+    let disassembler = cpu::CPU::new_shared(0);
+    disassembler.borrow_mut().set_references(code);
+    let mut disassembler = disassembler.borrow_mut();
+    disassembler.labels = virpc.labels().to_vec();
+
+    let mut end = match mem.slice(0, cpu::BSS as usize).iter().rposition(|&b| b != 0) {
+        Some(last) => last as u32 + 1,
+        None => 0,
+    };
+    if pc < cpu::BSS {
+        end = end.max(pc + 1);
+    }
+
+    let mut lines = Vec::new();
+    let mut address = 0;
+    while address < end {
+        let next = disassembler.disassemble(address);
+        let text = disassembler.instruction_to_text();
+        // address and label, instruction, bytes
+        let instruction = text.split('\t').map(|part| part.trim()).filter(|part| !part.is_empty())
+            .nth(1).unwrap_or("").to_string();
+        lines.push(Line { address, length: next - address, text: text.trim_end().to_string(), instruction });
+        address = next;
+    }
+    lines
+}
+
+fn listing_source() -> Value {
+    json::object(vec![("name", LISTING_NAME.into()), ("sourceReference", LISTING.into())])
+}
+
+// the label at or before pc, with the distance to it
+fn function_name(pc: u32, labels: &[cpu::Label]) -> String {
+    match labels.iter().filter(|l| l.address <= pc && l.address < cpu::BSS).max_by_key(|l| l.address) {
+        Some(label) if label.address == pc => label.tag.clone(),
+        Some(label) => format!("{}+{}", label.tag, pc - label.address),
+        None => format!("${:04X}", pc),
+    }
+}
+
+fn condition(breakpoint: &Value, labels: &[cpu::Label]) -> String {
+    breakpoints::resolve_labels(breakpoint.get("condition").as_str().unwrap_or(""), labels)
+}
+
+fn unverified(message: &str) -> Value {
+    json::object(vec![("verified", false.into()), ("message", message.into())])
+}
+
+// replace the breakpoints in old with the wanted ones (address, condition, line), leaving those
+// in keep. returns the addresses placed and the answer for every wanted breakpoint
+fn place_breakpoints(virpc: &mut virpc::Virpc, old: &[u32], keep: &[u32],
+                     wanted: Vec<(Result<u32, String>, String, Option<i64>)>) -> (Vec<u32>, Vec<Value>) {
+    let breakpoints = virpc.breakpoints();
+    for &address in old {
+        let still = wanted.iter().any(|w| w.0.as_ref().ok() == Some(&address));
+        if !still && !keep.contains(&address) {
+            breakpoints.remove(address);
+        }
+    }
+
+    let mut placed = Vec::new();
+    let mut answers = Vec::new();
+    for (address, condition, line) in wanted {
+        let result = address.and_then(|a| breakpoints.set_condition(a, &condition).map(|_| a));
+        let mut answer = match result {
+            Ok(address) => {
+                placed.push(address);
+                json::object(vec![("id", address.into()), ("verified", true.into()),
+                                  ("instructionReference", reference(address).into())])
+            }
+            Err(message) => unverified(&message),
+        };
+        if let (Some(line), Value::Object(ref mut fields)) = (line, &mut answer) {
+            fields.push(("line".to_string(), line.into()));
+        }
+        answers.push(answer);
+    }
+    (placed, answers)
+}
+
+// registers are named like in the breakpoint conditions
+fn register_name(n: u32) -> String {
+    match n {
+        0 => "pc".to_string(),
+        1 => "stack".to_string(),
+        INT_REGISTERS => "p".to_string(),
+        _ => format!("reg{}", n - 2),
+    }
+}
+
+fn register_number(name: &str) -> Option<u32> {
+    (0..=INT_REGISTERS).find(|&n| register_name(n) == name)
+}
+
+// the set flags, like "0x03 carry zero"
+fn status_text(p: u8) -> String {
+    let flags = [(cpu::StatusFlag::Carry as u8, "carry"), (cpu::StatusFlag::Zero as u8, "zero"),
+                 (cpu::StatusFlag::Overflow as u8, "overflow"), (cpu::StatusFlag::Negative as u8, "negative")];
+    let mut text = format!("0x{:02X}", p);
+    for (bit, name) in flags.iter() {
+        if p & bit != 0 {
+            text.push(' ');
+            text.push_str(name);
+        }
+    }
+    text
+}
+
+fn number_text(value: u32, hex: bool) -> String {
+    if hex { format!("0x{:08X}", value) } else { value.to_string() }
+}
+
+// a register or label as a variable
+fn variable(name: &str, virpc: &virpc::Virpc, hex: bool) -> Option<Value> {
+    let mem = virpc.memory.borrow();
+    if let Some(n) = register_number(name) {
+        if n == INT_REGISTERS {
+            let p = virpc.cpu.borrow().p;
+            return Some(json::object(vec![("name", name.into()), ("value", status_text(p).into()),
+                                          ("type", "flags".into()), ("variablesReference", 0u32.into())]));
+        }
+        let value = mem.peek_int_le(PC_REG + n * 4);
+        let mut fields = vec![
+            ("name", name.into()),
+            ("value", number_text(value, hex || n < 2).into()),
+            ("type", "int".into()),
+            ("evaluateName", name.into()),
+            ("variablesReference", 0u32.into()),
+        ];
+        // pc and stack point somewhere
+        if n < 2 {
+            fields.push(("memoryReference", reference(value).into()));
+        }
+        return Some(json::object(fields));
+    }
+
+    let label = virpc.labels().iter().find(|l| l.tag == name)?;
+    let (value, kind) = if label.address < cpu::BSS {
+        (format!("${:04X}", label.address), "code".to_string())
+    } else {
+        match label.size {
+            1 => (number_text(mem.peek_byte(label.address) as u32, hex), "byte".to_string()),
+            4 => (number_text(mem.peek_int_le(label.address), hex), "int".to_string()),
+            size => {
+                let bytes: Vec<String> = mem.slice(label.address, size.min(16) as usize).iter()
+                    .map(|b| format!("{:02X}", b)).collect();
+                (bytes.join(" "), format!("byte[{}]", size))
+            }
+        }
+    };
+    Some(json::object(vec![
+        ("name", name.into()),
+        ("value", value.into()),
+        ("type", kind.into()),
+        ("evaluateName", name.into()),
+        ("memoryReference", reference(label.address).into()),
+        ("variablesReference", 0u32.into()),
+    ]))
+}
+
+fn variables(args: &Value, virpc: &virpc::Virpc) -> Value {
+    let hex = args.get("format").get("hex").as_bool().unwrap_or(false);
+    let names: Vec<String> = match args.get("variablesReference").as_i64() {
+        Some(r) if r == SCOPE_REGISTERS as i64 => (0..=INT_REGISTERS).map(register_name).collect(),
+        Some(r) if r == SCOPE_LABELS as i64 => virpc.labels().iter().map(|l| l.tag.clone()).collect(),
+        _ => Vec::new(),
+    };
+    let variables: Vec<Value> = names.iter().filter_map(|name| variable(name, virpc, hex)).collect();
+    json::object(vec![("variables", variables.into())])
+}
+
+// the value can be anything evaluate takes
+fn set_variable(args: &Value, virpc: &mut virpc::Virpc) -> Result<Value, String> {
+    let name = args.get("name").as_str().unwrap_or("");
+    let value = value_of(args.get("value").as_str().unwrap_or(""), virpc)?;
+    match register_number(name) {
+        Some(INT_REGISTERS) => virpc.cpu.borrow_mut().p = value as u8,
+        Some(n) => virpc.memory.borrow_mut().write_block(PC_REG + n * 4, &value.to_le_bytes()),
+        None => {
+            let label = virpc.labels().iter().find(|l| l.tag == name).cloned()
+                .ok_or_else(|| format!("don't know {}", name))?;
+            if label.address < cpu::BSS {
+                return Err("code labels can't be changed".to_string());
+            }
+            let bytes = value.to_le_bytes();
+            let size = if label.size == 1 { 1 } else { 4 };
+            virpc.memory.borrow_mut().write_block(label.address, &bytes[..size]);
+        }
+    }
+    let hex = args.get("format").get("hex").as_bool().unwrap_or(false);
+    let variable = variable(name, virpc, hex).unwrap_or(Value::Null);
+    Ok(json::object(vec![("value", variable.get("value").clone())]))
+}
+
+// registers and labels by name, otherwise the operands of the breakpoint conditions with
+// labels for addresses: 5, 0xE000, [counter], b[reg0]
+fn evaluate(args: &Value, virpc: &mut virpc::Virpc) -> Result<Value, String> {
+    let expression = args.get("expression").as_str().unwrap_or("").trim();
+    let hex = args.get("format").get("hex").as_bool().unwrap_or(false);
+    if let Some(variable) = variable(expression, virpc, hex) {
+        let mut fields = vec![("result", variable.get("value").clone()), ("variablesReference", 0u32.into())];
+        if let Some(memory) = variable.get("memoryReference").as_str() {
+            fields.push(("memoryReference", memory.into()));
+        }
+        return Ok(json::object(fields));
+    }
+    let value = value_of(expression, virpc)?;
+    Ok(json::object(vec![("result", number_text(value, hex).into()), ("variablesReference", 0u32.into())]))
+}
+
+fn value_of(expression: &str, virpc: &virpc::Virpc) -> Result<u32, String> {
+    breakpoints::evaluate(&breakpoints::resolve_labels(expression, virpc.labels()), &virpc.memory.borrow())
+}
+
+fn data_breakpoint_info(args: &Value, virpc: &virpc::Virpc) -> Value {
+    let name = args.get("name").as_str().unwrap_or("");
+    let data = match register_number(name) {
+        Some(INT_REGISTERS) => None, // the flags aren't in memory
+        Some(n) => Some((PC_REG + n * 4, 4)),
+        None => virpc.labels().iter().find(|l| l.tag == name).map(|l| (l.address, l.size.max(1))),
+    };
+    match data {
+        Some((address, size)) => json::object(vec![
+            ("dataId", format!("{}/{}", reference(address), size).into()),
+            ("description", format!("{} ({} bytes at 0x{:08X})", name, size, address).into()),
+            ("accessTypes", vec!["read".into(), "write".into()].into()),
+            ("canPersist", false.into()),
+        ]),
+        None => json::object(vec![("dataId", Value::Null), ("description", format!("{} isn't in memory", name).into())]),
+    }
+}
+
+fn read_memory(args: &Value, virpc: &virpc::Virpc) -> Result<Value, String> {
+    let address = memory_address(args)?;
+    let count = (args.get("count").as_i64().unwrap_or(0).max(0) as usize).min(MAX_READ);
+    let mem = virpc.memory.borrow();
+    let bytes = mem.slice(address, count);
+    Ok(json::object(vec![
+        ("address", reference(address).into()),
+        ("data", base64(bytes).into()),
+        ("unreadableBytes", (count - bytes.len()).into()),
+    ]))
+}
+
+fn write_memory(args: &Value, virpc: &mut virpc::Virpc) -> Result<Value, String> {
+    let address = memory_address(args)?;
+    let data = from_base64(args.get("data").as_str().unwrap_or("")).ok_or("the data isn't base64")?;
+    virpc.memory.borrow_mut().write_block(address, &data);
+    Ok(json::object(vec![("bytesWritten", data.len().into())]))
+}
+
+// memoryReference plus offset
+fn memory_address(args: &Value) -> Result<u32, String> {
+    let address = args.get("memoryReference").as_str().and_then(parse_reference).ok_or("not an address")?;
+    Ok(address.wrapping_add(args.get("offset").as_i64().unwrap_or(0) as u32))
+}
+
+fn reference(address: u32) -> String {
+    format!("0x{:08X}", address)
+}
+
+// hexadecimal with 0x, or decimal
+fn parse_reference(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+// line breaks are skipped, padding only ends the text
+fn from_base64(text: &str) -> Option<Vec<u8>> {
+    let text: Vec<u8> = text.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    let end = text.iter().rposition(|&c| c != b'=').map_or(0, |i| i + 1);
+    if text.len() - end > 2 || end % 4 == 1 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(end / 4 * 3 + 2);
+    let (mut n, mut bits) = (0u32, 0);
+    for &c in text[..end].iter() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        n = (n << 6 | value) & 0xFFFFFF;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((n >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_base64() {
+        let cases = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("foobar", "Zm9vYmFy")];
+        for (bytes, text) in cases.iter() {
+            assert_eq!(base64(bytes.as_bytes()), *text);
+            assert_eq!(from_base64(text), Some(bytes.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for len in 0..bytes.len() {
+            assert_eq!(from_base64(&base64(&bytes[..len])), Some(bytes[..len].to_vec()));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_base64() {
+        assert_eq!(from_base64("Zm9v\nYmFy"), Some(b"foobar".to_vec()));
+        assert_eq!(from_base64("Zm9v!"), None);
+        assert_eq!(from_base64("Zm-v"), None);
+        assert_eq!(from_base64("Zg=v"), None);
+        assert_eq!(from_base64("Zg==="), None);
+        assert_eq!(from_base64("Zm9vY"), None);
+    }
+
+    #[test]
+    fn parses_references() {
+        assert_eq!(parse_reference("0xE000"), Some(0xE000));
+        assert_eq!(parse_reference("0Xe000"), Some(0xE000));
+        assert_eq!(parse_reference("57344"), Some(0xE000));
+        assert_eq!(parse_reference("0x"), None);
+        assert_eq!(parse_reference("E000"), None);
+    }
+}
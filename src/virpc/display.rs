@@ -0,0 +1,40 @@
+// the machine's screen and the memory debug window. minifb windows have to be created and
+// updated on the main thread (macOS insists), so the machine thread sends its frames here
+use crate::debugger;
+use crate::virpc;
+use crate::virpc::memory;
+use minifb::*;
+
+pub struct Display {
+    window: minifb::Window,
+    debugger: Option<debugger::Debugger>,
+}
+
+impl Display {
+    pub fn new(window_scale: Scale, debugger_on: bool) -> Display {
+        let mut window = Window::new("VirPC", virpc::SCREEN_WIDTH, virpc::SCREEN_HEIGHT,
+                                     WindowOptions { scale: window_scale, ..Default::default() }).unwrap();
+        window.set_position(75, 20);
+
+        Display {
+            window,
+            debugger: if debugger_on { Some(debugger::Debugger::new()) } else { None },
+        }
+    }
+
+    // show a finished frame, without one the window still processes its input
+    pub fn show(&mut self, frame: Option<&[u32]>) {
+        match frame {
+            Some(frame) => { let _ = self.window.update_with_buffer(frame, virpc::SCREEN_WIDTH, virpc::SCREEN_HEIGHT); }
+            None => self.window.update(),
+        }
+    }
+
+    // the debug window shows the editor's copy of the memory
+    pub fn render_debugger(&mut self, memory: &mut memory::MemShared, p: u8) {
+        if let Some(ref mut dbg) = self.debugger {
+            let pc = memory.borrow().peek_int_le(virpc::PC_REG);
+            dbg.render(memory, pc, p);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// DMA controller, moves blocks of memory while the cpu keeps running
+use crate::virpc::memory;
+use crate::virpc::cpu;
+use crate::virpc::snapshot;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io;
+
+pub type DmaShared = Rc<RefCell<Dma>>;
+
+// DMA registers
+pub const DMA_SRC: u32 = 0xF300;        // source address (int)
+pub const DMA_DST: u32 = 0xF304;        // destination address (int)
+pub const DMA_LEN: u32 = 0xF308;        // bytes to transfer, or bytes per row in 2D mode (int)
+pub const DMA_ROWS: u32 = 0xF30C;       // number of rows in 2D mode (int)
+pub const DMA_SRC_STRIDE: u32 = 0xF310; // distance between source rows in 2D mode (int)
+pub const DMA_DST_STRIDE: u32 = 0xF314; // distance between destination rows in 2D mode (int)
+pub const DMA_MODE: u32 = 0xF318;       // 0 = copy, 1 = fill, 2 = 2D copy (byte)
+pub const DMA_FILL: u32 = 0xF319;       // value written in fill mode (byte)
+pub const DMA_CONTROL: u32 = 0xF31A;    // bit 0: start, bit 1: interrupt on completion (byte)
+pub const DMA_STATUS: u32 = 0xF31B;     // bit 0: busy, bit 1: done (byte)
+
+// bytes moved for every cycle the DMA owns the bus
+const DMA_BYTES_PER_CYCLE: u32 = 4;
+
+pub enum DmaMode {
+    Copy,
+    Fill,
+    Copy2D,
+}
+
+pub struct Dma {
+    mem_ref: Option<memory::MemShared>,
+    cpu_ref: Option<cpu::CPUShared>,
+    busy: bool,
+    mode: DmaMode,
+    src: u32,
+    dst: u32,
+    len: u32,
+    rows: u32,
+    src_stride: u32,
+    dst_stride: u32,
+    fill: u8,
+    irq: bool,
+    row: u32,   // current row of the running transfer
+    col: u32,   // current byte within the row
+}
+
+impl Dma {
+    pub fn new_shared() -> DmaShared {
+        Rc::new(RefCell::new(Dma {
+            mem_ref: None,
+            cpu_ref: None,
+            busy: false,
+            mode: DmaMode::Copy,
+            src: 0,
+            dst: 0,
+            len: 0,
+            rows: 0,
+            src_stride: 0,
+            dst_stride: 0,
+            fill: 0,
+            irq: false,
+            row: 0,
+            col: 0,
+        }))
+    }
+
+    pub fn set_references(&mut self, memref: memory::MemShared, cpuref: cpu::CPUShared) {
+        self.mem_ref = Some(memref);
+        self.cpu_ref = Some(cpuref);
+    }
+
+    pub fn reset(&mut self) {
+        self.busy = false;
+        self.row = 0;
+        self.col = 0;
+        as_mut!(self.mem_ref).write_byte(DMA_CONTROL, 0);
+        as_mut!(self.mem_ref).write_byte(DMA_STATUS, 0);
+    }
+
+    // the transfer in progress, the registers are saved with memory
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.bool(self.busy);
+        w.u8(match self.mode { DmaMode::Copy => 0, DmaMode::Fill => 1, DmaMode::Copy2D => 2 });
+        for value in [self.src, self.dst, self.len, self.rows, self.src_stride, self.dst_stride] {
+            w.u32(value);
+        }
+        w.u8(self.fill);
+        w.bool(self.irq);
+        w.u32(self.row);
+        w.u32(self.col);
+    }
+
+    pub fn load(&mut self, r: &mut snapshot::Reader) -> io::Result<()> {
+        self.busy = r.bool()?;
+        self.mode = match r.u8()? { 0 => DmaMode::Copy, 1 => DmaMode::Fill, _ => DmaMode::Copy2D };
+        self.src = r.u32()?;
+        self.dst = r.u32()?;
+        self.len = r.u32()?;
+        self.rows = r.u32()?;
+        self.src_stride = r.u32()?;
+        self.dst_stride = r.u32()?;
+        self.fill = r.u8()?;
+        self.irq = r.bool()?;
+        self.row = r.u32()?;
+        self.col = r.u32()?;
+        Ok(())
+    }
+
+    // advance the DMA by a single cycle
+    pub fn update(&mut self) {
+        if !self.busy {
+            let control = as_mut!(self.mem_ref).read_byte(DMA_CONTROL);
+            if control & 0x01 == 0 {
+                return;
+            }
+            self.start(control);
+        }
+
+        for _ in 0..DMA_BYTES_PER_CYCLE {
+            if self.row >= self.rows {
+                self.finish();
+                return;
+            }
+            self.transfer_byte();
+        }
+    }
+
+    // *** private functions *** //
+
+    // latch the registers, so the guest can prepare the next transfer while this one runs
+    fn start(&mut self, control: u8) {
+        let mut mem = as_mut!(self.mem_ref);
+        self.src = mem.read_int_le(DMA_SRC);
+        self.dst = mem.read_int_le(DMA_DST);
+        self.len = mem.read_int_le(DMA_LEN);
+        self.fill = mem.read_byte(DMA_FILL);
+        self.mode = match mem.read_byte(DMA_MODE) {
+            1 => DmaMode::Fill,
+            2 => DmaMode::Copy2D,
+            _ => DmaMode::Copy,
+        };
+        match self.mode {
+            DmaMode::Copy2D => {
+                self.rows = mem.read_int_le(DMA_ROWS);
+                self.src_stride = mem.read_int_le(DMA_SRC_STRIDE);
+                self.dst_stride = mem.read_int_le(DMA_DST_STRIDE);
+            }
+            _ => {
+                self.rows = 1;
+                self.src_stride = 0;
+                self.dst_stride = 0;
+            }
+        }
+        if self.len == 0 {
+            self.rows = 0;
+        }
+        self.irq = control & 0x02 != 0;
+        self.row = 0;
+        self.col = 0;
+        self.busy = true;
+
+        mem.write_byte(DMA_CONTROL, control & !0x01);
+        mem.write_byte(DMA_STATUS, 0x01);
+    }
+
+    fn transfer_byte(&mut self) {
+        let dst = self.dst.wrapping_add(self.row.wrapping_mul(self.dst_stride)).wrapping_add(self.col);
+        let value = match self.mode {
+            DmaMode::Fill => self.fill,
+            _ => {
+                let src = self.src.wrapping_add(self.row.wrapping_mul(self.src_stride)).wrapping_add(self.col);
+                as_mut!(self.mem_ref).read_byte(src)
+            }
+        };
+        as_mut!(self.mem_ref).write_byte(dst, value);
+
+        self.col += 1;
+        if self.col >= self.len {
+            self.col = 0;
+            self.row += 1;
+        }
+    }
+
+    fn finish(&mut self) {
+        self.busy = false;
+        as_mut!(self.mem_ref).write_byte(DMA_STATUS, 0x02);
+        if self.irq {
+            as_mut!(self.cpu_ref).raise_interrupt(cpu::Interrupt::Dma);
+        }
+    }
+}
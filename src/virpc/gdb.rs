@@ -0,0 +1,497 @@
+// GDB remote serial protocol stub on a localhost TCP port. the machine thread polls it between
+// batches of cycles, so it works on the Virpc directly. gdb sees pc, stack and reg0-reg15 (the
+// ints from 0xF000 on) and the status flags as registers, as described by TARGET_XML
+use std::io;
+use crate::virpc;
+use crate::virpc::connection::Connection;
+use crate::virpc::memory;
+use crate::virpc::PC_REG;
+
+const INT_REGISTERS: u32 = 18; // pc, stack, reg0-reg15, the status flags come after them
+const PACKET_SIZE: usize = 0x4000;
+const MAX_READ: usize = PACKET_SIZE / 2 - 16; // bytes per memory read, as hex in one packet
+const MAX_INPUT: usize = 4 * PACKET_SIZE;      // more than gdb sends unanswered
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rproc.cpu">
+    <flags id="status" size="1">
+      <field name="carry" start="0" end="0"/>
+      <field name="zero" start="1" end="1"/>
+      <field name="overflow" start="6" end="6"/>
+      <field name="negative" start="7" end="7"/>
+    </flags>
+    <reg name="pc" bitsize="32" type="code_ptr" regnum="0"/>
+    <reg name="stack" bitsize="32" type="data_ptr"/>
+    <reg name="reg0" bitsize="32" type="uint32"/>
+    <reg name="reg1" bitsize="32" type="uint32"/>
+    <reg name="reg2" bitsize="32" type="uint32"/>
+    <reg name="reg3" bitsize="32" type="uint32"/>
+    <reg name="reg4" bitsize="32" type="uint32"/>
+    <reg name="reg5" bitsize="32" type="uint32"/>
+    <reg name="reg6" bitsize="32" type="uint32"/>
+    <reg name="reg7" bitsize="32" type="uint32"/>
+    <reg name="reg8" bitsize="32" type="uint32"/>
+    <reg name="reg9" bitsize="32" type="uint32"/>
+    <reg name="reg10" bitsize="32" type="uint32"/>
+    <reg name="reg11" bitsize="32" type="uint32"/>
+    <reg name="reg12" bitsize="32" type="uint32"/>
+    <reg name="reg13" bitsize="32" type="uint32"/>
+    <reg name="reg14" bitsize="32" type="uint32"/>
+    <reg name="reg15" bitsize="32" type="uint32"/>
+    <reg name="p" bitsize="8" type="status"/>
+  </feature>
+</target>
+"#;
+
+// what came in from gdb
+enum Input {
+    Packet(String),
+    Corrupt,   // a packet with a bad checksum
+    Nack,      // the last packet didn't arrive intact
+    Interrupt, // ctrl-c
+}
+
+pub struct Server {
+    connection: Connection, // one gdb at a time
+    acks: bool,        // until gdb asks for no-ack mode
+    waiting: bool,     // continued, the stop reply is due when the machine stops
+    interrupted: bool, // stopped by ctrl-c
+    last: Vec<u8>,     // last packet sent, to send again on a nack
+}
+
+impl Server {
+    pub fn bind(port: u16) -> io::Result<Server> {
+        Ok(Server {
+            connection: Connection::bind(port, MAX_INPUT)?,
+            acks: true,
+            waiting: false,
+            interrupted: false,
+            last: Vec::new(),
+        })
+    }
+
+    // accept a connection, answer what gdb sent and report a stop, never blocks.
+    // returns true when gdb changed the machine
+    pub fn poll(&mut self, virpc: &mut virpc::Virpc) -> bool {
+        if !self.connection.is_connected() && !self.accept() {
+            return false;
+        }
+        if !self.connection.receive() {
+            self.disconnect();
+            return false;
+        }
+
+        let mut changed = false;
+        while let Some(input) = take_input(&mut self.connection.input, self.acks) {
+            match input {
+                Input::Packet(packet) => {
+                    if self.acks {
+                        self.write(b"+");
+                    }
+                    changed = true;
+                    if let Some(reply) = self.handle(&packet, virpc) {
+                        self.send(&reply);
+                    }
+                }
+                Input::Corrupt => self.write(b"-"), // and dropped
+                Input::Nack => {
+                    let last = self.last.clone();
+                    self.write(&last);
+                }
+                Input::Interrupt => {
+                    if self.waiting {
+                        virpc.stop();
+                        self.interrupted = true;
+                        changed = true;
+                    }
+                }
+            }
+            if !self.connection.is_connected() {
+                return changed;
+            }
+        }
+
+        // breakpoint, watchpoint, ctrl-c or the editor
+        if self.waiting && !virpc.status() {
+            self.waiting = false;
+            let reply = if self.interrupted { "S02".to_string() } else { stop_reply(virpc) };
+            self.interrupted = false;
+            self.send(&reply);
+        }
+        changed
+    }
+
+    // *** private functions *** //
+
+    fn accept(&mut self) -> bool {
+        if !self.connection.accept() {
+            return false;
+        }
+        self.acks = true;
+        self.waiting = false;
+        self.interrupted = false;
+        true
+    }
+
+    fn disconnect(&mut self) {
+        self.connection.close();
+        self.waiting = false;
+    }
+
+    // the reply to a packet, None when it comes later (continue) or not at all
+    fn handle(&mut self, packet: &str, virpc: &mut virpc::Virpc) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            "?" => {
+                // gdb expects a stopped machine when it attaches
+                virpc.stop();
+                stop_reply(virpc)
+            }
+            "g" => {
+                let mut reply = String::new();
+                for n in 0..=INT_REGISTERS {
+                    reply.push_str(&hex(&register(virpc, n)));
+                }
+                reply
+            }
+            "G" => {
+                let bytes = match from_hex(args) {
+                    Some(bytes) if bytes.len() > INT_REGISTERS as usize * 4 => bytes,
+                    _ => return Some("E01".to_string()),
+                };
+                for (n, chunk) in bytes.chunks(4).enumerate().take(INT_REGISTERS as usize + 1) {
+                    set_register(virpc, n as u32, chunk);
+                }
+                "OK".to_string()
+            }
+            "p" => match u32::from_str_radix(args, 16) {
+                Ok(n) if n <= INT_REGISTERS => hex(&register(virpc, n)),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let (n, value) = args.split_once('=').unwrap_or((args, ""));
+                match (u32::from_str_radix(n, 16), from_hex(value)) {
+                    (Ok(n), Some(value)) if n <= INT_REGISTERS => {
+                        set_register(virpc, n, &value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match address_and_length(args) {
+                Some((address, length)) => {
+                    let mem = virpc.memory.borrow();
+                    let mut bytes = mem.slice(address, length.min(MAX_READ)).to_vec();
+                    // the void beyond RAM reads as 0, like it does for the cpu
+                    bytes.resize(length.min(MAX_READ), 0);
+                    hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let (range, data) = args.split_once(':').unwrap_or((args, "-"));
+                match (address_and_length(range), from_hex(data)) {
+                    (Some((address, length)), Some(data)) if data.len() == length => {
+                        virpc.memory.borrow_mut().write_block(address, &data);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "c" => {
+                if let Ok(address) = u32::from_str_radix(args, 16) {
+                    virpc.cpu.borrow_mut().set_pc(address);
+                }
+                virpc.continue_cpu();
+                self.waiting = true;
+                return None;
+            }
+            "s" => {
+                if let Ok(address) = u32::from_str_radix(args, 16) {
+                    virpc.cpu.borrow_mut().set_pc(address);
+                }
+                virpc.continue_cpu();
+                virpc.run();
+                virpc.stop();
+                stop_reply(virpc)
+            }
+            "Z" | "z" => breakpoint(virpc, command == "Z", args),
+            "q" => query(args),
+            "Q" if args == "StartNoAckMode" => {
+                self.send("OK");
+                self.acks = false;
+                return None;
+            }
+            "H" | "T" => "OK".to_string(), // the one thread is always there
+            "D" => {
+                virpc.continue_cpu();
+                self.send("OK");
+                self.disconnect();
+                return None;
+            }
+            "k" => {
+                virpc.stop();
+                self.disconnect();
+                return None;
+            }
+            _ => String::new(), // not supported
+        };
+        Some(reply)
+    }
+
+    fn send(&mut self, data: &str) {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data.as_bytes());
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(data.as_bytes())).as_bytes());
+        self.write(&packet);
+        self.last = packet;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.connection.is_connected() && !self.connection.write(bytes) {
+            self.disconnect();
+        }
+    }
+}
+
+// why the machine stopped, as a stop reply with signal 5 (trap)
+fn stop_reply(virpc: &mut virpc::Virpc) -> String {
+    if let Some(hit) = virpc.watch_hit() {
+        let kind = if hit.watch == memory::Watch::Read { "rwatch" } else { "watch" };
+        return format!("T05{}:{:x};", kind, hit.address);
+    }
+    let pc = virpc.cpu.borrow().get_pc();
+    if virpc.breakpoints().get(pc).is_some() {
+        "T05swbreak:;".to_string()
+    } else {
+        "S05".to_string()
+    }
+}
+
+// register n in target byte order, the ints are memory from PC_REG on
+fn register(virpc: &virpc::Virpc, n: u32) -> Vec<u8> {
+    if n < INT_REGISTERS {
+        virpc.memory.borrow().peek_int_le(PC_REG + n * 4).to_le_bytes().to_vec()
+    } else {
+        vec![virpc.cpu.borrow().p]
+    }
+}
+
+fn set_register(virpc: &mut virpc::Virpc, n: u32, value: &[u8]) {
+    if n < INT_REGISTERS {
+        let mut bytes = [0u8; 4];
+        let length = value.len().min(4);
+        bytes[..length].copy_from_slice(&value[..length]);
+        virpc.memory.borrow_mut().write_block(PC_REG + n * 4, &bytes);
+    } else if let Some(&p) = value.first() {
+        virpc.cpu.borrow_mut().p = p;
+    }
+}
+
+// Z0/Z1 (software, hardware) become breakpoints, Z2/Z3 write and read watchpoints.
+// Z4 (access) has no watchpoint kind of its own, so it is not supported
+fn breakpoint(virpc: &mut virpc::Virpc, set: bool, args: &str) -> String {
+    let mut parts = args.split(',');
+    let kind = parts.next().unwrap_or("");
+    let address = parts.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+    let size = parts.next().and_then(|s| u32::from_str_radix(s, 16).ok()).unwrap_or(1);
+    let address = match address {
+        Some(address) => address,
+        None => return "E01".to_string(),
+    };
+    let watch = match kind {
+        "0" | "1" => {
+            let breakpoints = virpc.breakpoints();
+            if !set {
+                breakpoints.remove(address);
+            } else if breakpoints.get(address).is_none() {
+                breakpoints.toggle(address);
+            }
+            return "OK".to_string();
+        }
+        "2" => memory::Watch::Write,
+        "3" => memory::Watch::Read,
+        _ => return String::new(),
+    };
+    let mut mem = virpc.memory.borrow_mut();
+    if set {
+        mem.watch(address, size, watch);
+    } else {
+        mem.unwatch(address);
+    }
+    "OK".to_string()
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+", PACKET_SIZE)
+    } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        match address_and_length(range) {
+            Some((offset, length)) => {
+                let xml = TARGET_XML.as_bytes();
+                let start = (offset as usize).min(xml.len());
+                let end = start.saturating_add(length).min(xml.len());
+                let more = if end < xml.len() { "m" } else { "l" };
+                format!("{}{}", more, escape(&xml[start..end]))
+            }
+            None => "E01".to_string(),
+        }
+    } else if args.starts_with("Xfer:features:read:") {
+        "E00".to_string()
+    } else {
+        match args {
+            "Attached" => "1".to_string(), // gdb detaches on quit, the machine keeps going
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+// take the next complete input off the front of what came in, None until it is all there.
+// the checksum is only checked while gdb sends acks
+fn take_input(input: &mut Vec<u8>, checked: bool) -> Option<Input> {
+    loop {
+        match *input.first()? {
+            b'$' => {
+                let end = input.iter().position(|&b| b == b'#')?;
+                if input.len() < end + 3 {
+                    return None;
+                }
+                let data: Vec<u8> = input[1..end].to_vec();
+                let checksum = std::str::from_utf8(&input[end + 1..end + 3]).ok()
+                    .and_then(|text| u8::from_str_radix(text, 16).ok());
+                input.drain(..end + 3);
+                if checked && checksum != Some(checksum_of(&data)) {
+                    return Some(Input::Corrupt);
+                }
+                return Some(Input::Packet(String::from_utf8_lossy(&data).into_owned()));
+            }
+            b'-' => {
+                input.remove(0);
+                return Some(Input::Nack);
+            }
+            0x03 => {
+                input.remove(0);
+                return Some(Input::Interrupt);
+            }
+            _ => { input.remove(0); } // acks and noise
+        }
+    }
+}
+
+// "address,length" in hex
+fn address_and_length(text: &str) -> Option<(u32, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// from_str_radix takes a sign as well, so the digits are checked first
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+// binary data in a reply, the packet characters are sent as } and the character xor 0x20
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b == b'#' || b == b'$' || b == b'}' || b == b'*' {
+            escaped.push('}');
+            escaped.push((b ^ 0x20) as char);
+        } else {
+            escaped.push(b as char);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(input: &mut Vec<u8>, checked: bool) -> Option<String> {
+        match take_input(input, checked) {
+            Some(Input::Packet(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn takes_packets_with_their_checksum() {
+        let mut input = b"+$OK#9a$m0,4#fd".to_vec();
+        assert_eq!(packet(&mut input, true), Some("OK".to_string()));
+        assert_eq!(packet(&mut input, true), Some("m0,4".to_string()));
+        assert!(input.is_empty());
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_packet() {
+        let mut input = b"$OK#9".to_vec();
+        assert!(take_input(&mut input, true).is_none());
+        assert_eq!(input, b"$OK#9");
+        input.push(b'a');
+        assert_eq!(packet(&mut input, true), Some("OK".to_string()));
+    }
+
+    #[test]
+    fn drops_a_packet_with_a_bad_checksum() {
+        let mut input = b"$OK#00$OK#zz$OK#9a".to_vec();
+        assert!(matches!(take_input(&mut input, true), Some(Input::Corrupt)));
+        assert!(matches!(take_input(&mut input, true), Some(Input::Corrupt)));
+        assert_eq!(packet(&mut input, true), Some("OK".to_string()));
+
+        // in no-ack mode the checksum isn't checked
+        let mut input = b"$OK#00".to_vec();
+        assert_eq!(packet(&mut input, false), Some("OK".to_string()));
+    }
+
+    #[test]
+    fn takes_nacks_and_interrupts() {
+        let mut input = b"x-\x03".to_vec();
+        assert!(matches!(take_input(&mut input, true), Some(Input::Nack)));
+        assert!(matches!(take_input(&mut input, true), Some(Input::Interrupt)));
+        assert!(take_input(&mut input, true).is_none());
+    }
+
+    #[test]
+    fn converts_hex() {
+        assert_eq!(hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("00AB"), Some(vec![0x00, 0xab]));
+        assert_eq!(from_hex(""), Some(vec![]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("+1"), None);
+    }
+
+    #[test]
+    fn reads_address_and_length() {
+        assert_eq!(address_and_length("e000,10"), Some((0xE000, 0x10)));
+        assert_eq!(address_and_length("e000"), None);
+        assert_eq!(address_and_length("e000,"), None);
+        assert_eq!(address_and_length("g,1"), None);
+    }
+
+    #[test]
+    fn escapes_packet_characters() {
+        assert_eq!(escape(b"a#b$c}d*e"), "a}\x03b}\x04c}]d}\ne");
+    }
+}
@@ -0,0 +1,24 @@
+// runs a program for a number of frames without the windows or the editor, so scripts can
+// capture its screen
+use crate::virpc;
+use std::io;
+
+// run the program at full speed, recording every nth frame when a prefix is given, then save
+// the last frame unless the filename is empty
+pub fn run(prg_file: &str, frames: u32, screenshot: &str, record: &str, every: u32) -> io::Result<()> {
+    let mut virpc = virpc::Virpc::new(prg_file);
+    if !record.is_empty() {
+        virpc.start_recording(record, every);
+    }
+    virpc.reset();
+    virpc.continue_cpu();
+    virpc.run_frames(frames);
+    virpc.stop_recording();
+
+    if screenshot.is_empty() {
+        return Ok(());
+    }
+    virpc.screenshot(screenshot).map_err(|why| {
+        io::Error::new(why.kind(), format!("Couldn't save {}: {}", screenshot, why))
+    })
+}
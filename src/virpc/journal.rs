@@ -0,0 +1,117 @@
+// bounded history of the executed instructions, with the memory writes and flags of each,
+// so execution can be stepped back and the last write to an address can be found
+use std::collections::VecDeque;
+use crate::virpc::calls;
+use crate::virpc::memory;
+use crate::virpc::video;
+
+pub const DEFAULT_SIZE: usize = 100000; // instructions kept
+
+// the state of the cpu and the beam before an instruction
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub pc: u32,
+    pub p: u8,
+    pub irq_armed: bool,
+    pub cycle: u32,
+    pub beam: video::Beam,
+    pub calls: usize, // the depth of the call stack
+    writes: usize,  // logged writes of this instruction, at the end of the writes queue
+    returns: usize, // calls it returned from, at the end of the returns queue
+}
+
+impl Entry {
+    pub fn new(pc: u32, p: u8, irq_armed: bool, cycle: u32, beam: video::Beam, calls: usize) -> Entry {
+        Entry { pc, p, irq_armed, cycle, beam, calls, writes: 0, returns: 0 }
+    }
+}
+
+// an answer to "who last wrote this address"
+#[derive(Clone, PartialEq)]
+pub struct LastWrite {
+    pub address: u32,
+    pub pc: u32,    // the instruction that wrote it
+    pub cycle: u32,
+    pub old: u8,
+    pub new: u8,
+}
+
+pub struct Journal {
+    size: usize, // 0 keeps nothing
+    entries: VecDeque<Entry>,
+    writes: VecDeque<memory::LoggedWrite>,
+    returns: VecDeque<u32>, // where the calls an instruction returned from continued
+}
+
+impl Journal {
+    pub fn new(size: usize) -> Journal {
+        Journal {
+            size,
+            entries: VecDeque::new(),
+            writes: VecDeque::new(),
+            returns: VecDeque::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.writes.clear();
+        self.returns.clear();
+    }
+
+    // add an instruction with the writes logged since its start and the calls it returned from,
+    // dropping the oldest one when full
+    pub fn record(&mut self, mut entry: Entry, returned: &[u32], mem: &mut memory::Memory) {
+        let before = self.writes.len();
+        self.writes.extend(mem.drain_log());
+        entry.writes = self.writes.len() - before;
+        self.returns.extend(returned);
+        entry.returns = returned.len();
+        self.entries.push_back(entry);
+
+        if self.entries.len() > self.size {
+            if let Some(oldest) = self.entries.pop_front() {
+                self.writes.drain(..oldest.writes);
+                self.returns.drain(..oldest.returns);
+            }
+        }
+    }
+
+    // undo the writes and the calls of the last instruction, and return the state from before it
+    pub fn step_back(&mut self, mem: &mut memory::Memory, calls: &mut calls::CallStack<()>) -> Option<Entry> {
+        let entry = self.entries.pop_back()?;
+        for _ in 0..entry.writes {
+            if let Some(write) = self.writes.pop_back() {
+                mem.undo(&write);
+            }
+        }
+        // the calls it returned from came off the top, the ones it made are on top of those
+        calls.truncate(entry.calls - entry.returns);
+        for ret in self.returns.drain(self.returns.len() - entry.returns..) {
+            calls.push(ret, ());
+        }
+        Some(entry)
+    }
+
+    pub fn last_write(&self, address: u32) -> Option<LastWrite> {
+        let mut end = self.writes.len();
+        for entry in self.entries.iter().rev() {
+            let start = end - entry.writes;
+            for write in self.writes.range(start..end).rev() {
+                if write.address == address {
+                    return Some(LastWrite { address, pc: entry.pc, cycle: entry.cycle, old: write.old, new: write.new });
+                }
+            }
+            end = start;
+        }
+        None
+    }
+}
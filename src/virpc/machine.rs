@@ -0,0 +1,675 @@
+// runs the Virpc on a thread of its own. The editor controls it with commands, and keeps a
+// copy of its memory that is refreshed from the changes in the states the machine sends back
+use crate::virpc;
+use crate::virpc::breakpoints;
+use crate::virpc::clock;
+use crate::virpc::coverage;
+use crate::virpc::cpu;
+use crate::virpc::display;
+use crate::virpc::journal;
+use crate::virpc::memory;
+use crate::virpc::profiler;
+use crate::virpc::snapshot;
+use crate::virpc::trace;
+use std::fs;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+const STATE_FREQ: f64 = 10.0; // states per second, more are sent right after commands
+const TICK_MS: u64 = 5;          // pause between batches of cycles
+
+pub enum Command {
+    Run,
+    Stop,
+    Step,
+    StepOver,
+    StepOut,
+    RunTo(u32),
+    RunInstructions(u64),
+    StepBack,
+    RunBack,
+    WhoWrote(u32),
+    Reset,
+    ToggleBreakpoint(u32),
+    RemoveBreakpoint(u32),
+    EnableBreakpoint(u32, bool),
+    BreakpointCondition(u32, String),
+    Watch(u32, u32, memory::Watch),
+    Unwatch(u32),
+    WriteMemory(u32, Vec<u8>),
+    Screenshot(String),
+    SaveSnapshot(String, Vec<cpu::Label>),
+    LoadSnapshot(snapshot::Snapshot),
+    StartRecording(String, u32),
+    StopRecording,
+    StartTrace(String),
+    StopTrace,
+    Profile(bool),
+    ClearProfile,
+    RequestProfile,
+    Coverage(bool),
+    ClearCoverage,
+    Labels(Vec<cpu::Label>),
+    Quit(String), // saves a screenshot to this file first, unless it is empty
+}
+
+pub struct State {
+    seq: u32, // last command handled
+    running: bool,
+    recording: bool,
+    tracing: bool,
+    ips: u32,
+    breakpoints: breakpoints::Breakpoints,
+    watchpoints: Vec<memory::Watchpoint>,
+    watch_hit: Option<memory::WatchHit>,
+    journal_len: usize,
+    last_write: Option<(u32, Option<journal::LastWrite>)>, // answer to the last WhoWrote
+    result: Option<(u32, Result<(), String>)>, // of the last command that can fail, with its seq
+    profiling: bool,
+    profile: Option<Option<profiler::Profile>>, // a copy, only when the editor asked for it
+    covering: bool,
+    coverage: Option<coverage::Coverage>,
+    p: u8, // processor status, for the debug window
+    memory: Vec<(u32, Vec<u8>)>, // what changed since the last state that was sent
+}
+
+// the editor's handle to the machine thread
+pub struct Machine {
+    pub memory: memory::MemShared, // editor side copy of the machine's memory
+    baseline: Vec<u8>,             // memory as last received, to find the editor's own changes
+    commands: mpsc::Sender<(u32, Command)>,
+    states: mpsc::Receiver<State>,
+    frames: mpsc::Receiver<Vec<u32>>,
+    display: Option<display::Display>, // the windows stay on this thread
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+    seq: u32, // last command sent
+    running: bool,
+    recording: bool,
+    tracing: bool,
+    ips: u32,
+    breakpoints: breakpoints::Breakpoints, // changed here right away, the hit counts come from the machine
+    watchpoints: Vec<memory::Watchpoint>,
+    watch_hit: Option<memory::WatchHit>,
+    journal_len: usize,
+    last_write: Option<(u32, Option<journal::LastWrite>)>,
+    result: Option<(u32, Result<(), String>)>,
+    profiling: bool,
+    profile: Option<profiler::Profile>, // as last fetched
+    covering: bool,
+    coverage: Option<coverage::Coverage>,
+    p: u8,
+    labels: Vec<cpu::Label>, // as last sent
+}
+
+impl Machine {
+    // setup builds the Virpc on the new thread, so its shared parts never leave it. the display
+    // stays here and gets the frames, without one the machine runs without a window.
+    // returns once the first state arrived, with the memory copy filled in
+    pub fn spawn<F>(display: Option<display::Display>, setup: F) -> Machine
+        where F: FnOnce() -> virpc::Virpc + Send + 'static {
+        let (command_tx, command_rx) = mpsc::channel();
+        let (state_tx, state_rx) = mpsc::sync_channel(1);
+        let (frame_tx, frame_rx) = mpsc::sync_channel(1);
+        let frame_tx = if display.is_some() { Some(frame_tx) } else { None };
+        let thread = thread::spawn(move || machine_thread(setup, command_rx, state_tx, frame_tx));
+
+        let mut machine = Machine {
+            memory: memory::Memory::new_shared(),
+            baseline: vec![0; memory::MEM_SIZE],
+            commands: command_tx,
+            states: state_rx,
+            frames: frame_rx,
+            display,
+            thread: Some(thread),
+            seq: 0,
+            running: false,
+            recording: false,
+            tracing: false,
+            ips: 0,
+            breakpoints: breakpoints::Breakpoints::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            journal_len: 0,
+            last_write: None,
+            result: None,
+            profiling: false,
+            profile: None,
+            covering: false,
+            coverage: None,
+            p: 0,
+            labels: Vec::new(),
+        };
+        match machine.states.recv() {
+            Ok(mut state) => {
+                machine.apply_memory(&mut state);
+                machine.apply(state);
+            }
+            Err(_) => panic!("The machine stopped while starting up"),
+        }
+        machine
+    }
+
+    // send the editor's changes to the machine, then take over the newest state and show the
+    // newest frame. returns true when a state was applied
+    pub fn sync(&mut self) -> bool {
+        self.send_changes();
+
+        // every state's memory changes count, the rest only of the newest
+        let mut newest = None;
+        while let Ok(mut state) = self.states.try_recv() {
+            self.apply_memory(&mut state);
+            newest = Some(state);
+        }
+        let applied = match newest {
+            // older states don't have the effect of the last commands yet
+            Some(state) if state.seq >= self.seq => {
+                self.apply(state);
+                true
+            }
+            _ => false,
+        };
+
+        if let Some(ref mut display) = self.display {
+            let frame = self.frames.try_iter().last();
+            display.show(frame.as_deref());
+            display.render_debugger(&mut self.memory, self.p);
+        }
+        applied
+    }
+
+    pub fn continue_cpu(&mut self) {
+        self.running = true;
+        self.send(Command::Run);
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.send(Command::Stop);
+    }
+
+    pub fn step(&mut self) {
+        self.send(Command::Step);
+    }
+
+    // a CLL runs until its call returned, other instructions are a single step
+    pub fn step_over(&mut self) {
+        self.running = true;
+        self.send(Command::StepOver);
+    }
+
+    // run until the current call returns, fails when execution isn't in a call
+    pub fn step_out(&mut self) -> Result<(), String> {
+        self.running = true;
+        self.send(Command::StepOut);
+        self.wait_result()
+    }
+
+    // run until execution gets to an address
+    pub fn run_to(&mut self, adr : u32) {
+        self.running = true;
+        self.send(Command::RunTo(adr));
+    }
+
+    pub fn run_instructions(&mut self, count : u64) {
+        self.running = true;
+        self.send(Command::RunInstructions(count));
+    }
+
+    pub fn step_back(&mut self) {
+        self.send(Command::StepBack);
+    }
+
+    // step back to the previous breakpoint
+    pub fn run_back(&mut self) {
+        self.send(Command::RunBack);
+    }
+
+    // instructions that can be stepped back
+    pub fn journal_len(&self) -> usize {
+        self.journal_len
+    }
+
+    // waits for the machine's answer, None when no write to adr is left in the journal
+    pub fn who_wrote(&mut self, adr : u32) -> Option<journal::LastWrite> {
+        self.send(Command::WhoWrote(adr));
+        self.wait();
+        match self.last_write {
+            Some((address, ref answer)) if address == adr => answer.clone(),
+            _ => None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.send(Command::Reset);
+    }
+
+    pub fn status(&mut self) -> bool {
+        self.running
+    }
+
+    pub fn breakpoints(&self) -> &breakpoints::Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn toggle_breakpoint(&mut self, adr : u32) {
+        self.breakpoints.toggle(adr);
+        self.send(Command::ToggleBreakpoint(adr));
+    }
+
+    pub fn remove_breakpoint(&mut self, adr : u32) {
+        self.breakpoints.remove(adr);
+        self.send(Command::RemoveBreakpoint(adr));
+    }
+
+    pub fn enable_breakpoint(&mut self, adr : u32, enabled: bool) {
+        self.breakpoints.enable(adr, enabled);
+        self.send(Command::EnableBreakpoint(adr, enabled));
+    }
+
+    // the condition is checked here, so the machine only gets valid ones
+    pub fn set_breakpoint_condition(&mut self, adr : u32, condition: &str) -> Result<(), String> {
+        self.breakpoints.set_condition(adr, condition)?;
+        self.send(Command::BreakpointCondition(adr, String::from(condition)));
+        Ok(())
+    }
+
+    pub fn watch(&mut self, adr : u32, size : u32, watch : memory::Watch) {
+        self.send(Command::Watch(adr, size, watch));
+    }
+
+    pub fn unwatch(&mut self, adr : u32) {
+        self.send(Command::Unwatch(adr));
+    }
+
+    pub fn watchpoints(&self) -> &[memory::Watchpoint] {
+        &self.watchpoints
+    }
+
+    // the access that stopped the machine on a watchpoint
+    pub fn watch_hit(&self) -> Option<&memory::WatchHit> {
+        self.watch_hit.as_ref()
+    }
+
+    pub fn ips(&self) -> u32 {
+        self.ips
+    }
+
+    // waits until it is written
+    pub fn screenshot(&mut self, filename: &str) -> Result<(), String> {
+        self.send(Command::Screenshot(String::from(filename)));
+        self.wait_result()
+    }
+
+    // save the machine with the editor's labels, waits until it is written
+    pub fn save_snapshot(&mut self, filename: &str, labels: Vec<cpu::Label>) -> Result<(), String> {
+        self.send(Command::SaveSnapshot(String::from(filename), labels));
+        self.wait_result()
+    }
+
+    // the machine continues from the file, stopped. returns the labels that were saved with it
+    pub fn load_snapshot(&mut self, filename: &str) -> Result<Vec<cpu::Label>, String> {
+        let snap = snapshot::Snapshot::load(filename).map_err(|why| why.to_string())?;
+        let labels = snapshot::load_labels(&snap).map_err(|why| why.to_string())?;
+        self.send(Command::LoadSnapshot(snap));
+        self.wait_result()?;
+        Ok(labels)
+    }
+
+    pub fn start_recording(&mut self, prefix: &str, every: u32) {
+        self.recording = true;
+        self.send(Command::StartRecording(String::from(prefix), every));
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recording = false;
+        self.send(Command::StopRecording);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    // trace every executed instruction to a .csv or json lines file, waits until it is created
+    pub fn start_trace(&mut self, filename: &str) -> Result<(), String> {
+        self.send(Command::StartTrace(String::from(filename)));
+        self.wait_result()
+    }
+
+    pub fn stop_trace(&mut self) {
+        self.tracing = false;
+        self.send(Command::StopTrace);
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracing
+    }
+
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profiling = profiling;
+        self.send(Command::Profile(profiling));
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profiling
+    }
+
+    pub fn clear_profile(&mut self) {
+        self.profile = None;
+        self.send(Command::ClearProfile);
+    }
+
+    // as last fetched, kept after profiling stopped until it is cleared
+    pub fn profile(&self) -> Option<&profiler::Profile> {
+        self.profile.as_ref()
+    }
+
+    // copying the profile takes a while, so the machine only sends it when asked. waits for it
+    pub fn fetch_profile(&mut self) -> Option<&profiler::Profile> {
+        self.send(Command::RequestProfile);
+        self.wait();
+        self.profile.as_ref()
+    }
+
+    pub fn set_coverage(&mut self, covering: bool) {
+        self.covering = covering;
+        self.send(Command::Coverage(covering));
+    }
+
+    pub fn is_covering(&self) -> bool {
+        self.covering
+    }
+
+    pub fn clear_coverage(&mut self) {
+        self.coverage = None;
+        self.send(Command::ClearCoverage);
+    }
+
+    // as of the last state, kept after covering stopped until it is cleared
+    pub fn coverage(&self) -> Option<&coverage::Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // stop covering and wait for the complete coverage
+    pub fn stop_coverage(&mut self) -> Option<&coverage::Coverage> {
+        self.set_coverage(false);
+        self.wait();
+        self.coverage.as_ref()
+    }
+
+    // the editor's labels, for the debuggers on the machine's side. only sent when they changed
+    pub fn set_labels(&mut self, labels: &[cpu::Label]) {
+        if labels != &self.labels[..] {
+            self.labels = labels.to_vec();
+            self.send(Command::Labels(labels.to_vec()));
+        }
+    }
+
+    // wait until a state has the effect of all commands sent, or a second passed
+    pub fn wait(&mut self) {
+        for _ in 0..200 {
+            if self.sync() {
+                return;
+            }
+            thread::sleep(std::time::Duration::from_millis(TICK_MS));
+        }
+    }
+
+    // wait for the machine to stop by itself, false when it still runs after about a second
+    pub fn wait_stopped(&mut self) -> bool {
+        for _ in 0..200 {
+            if self.sync() && !self.running {
+                return true;
+            }
+            thread::sleep(std::time::Duration::from_millis(TICK_MS));
+        }
+        false
+    }
+
+    // stop the machine thread, saving a screenshot first unless the filename is empty. a thread
+    // that panicked is an error as well
+    pub fn quit(&mut self, screenshot: &str) -> io::Result<()> {
+        self.send(Command::Quit(String::from(screenshot)));
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|panic| {
+                let why = match panic.downcast_ref::<&str>() {
+                    Some(why) => why.to_string(),
+                    None => panic.downcast_ref::<String>().cloned().unwrap_or_default(),
+                };
+                Err(io::Error::new(io::ErrorKind::Other, format!("The machine stopped with a panic: {}", why)))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // *** private functions *** //
+
+    fn send(&mut self, command: Command) {
+        self.seq += 1;
+        let _ = self.commands.send((self.seq, command));
+    }
+
+    // the result of the last command sent
+    fn wait_result(&mut self) -> Result<(), String> {
+        let seq = self.seq;
+        self.wait();
+        match self.result {
+            Some((n, ref result)) if n == seq => result.clone(),
+            _ => Err("the machine didn't answer".to_string()),
+        }
+    }
+
+    fn apply(&mut self, state: State) {
+        self.running = state.running;
+        self.recording = state.recording;
+        self.tracing = state.tracing;
+        self.ips = state.ips;
+        self.breakpoints = state.breakpoints;
+        self.watchpoints = state.watchpoints;
+        self.watch_hit = state.watch_hit;
+        self.journal_len = state.journal_len;
+        self.last_write = state.last_write;
+        if state.result.is_some() {
+            self.result = state.result;
+        }
+        self.profiling = state.profiling;
+        if let Some(profile) = state.profile {
+            self.profile = profile;
+        }
+        self.covering = state.covering;
+        self.coverage = state.coverage;
+        self.p = state.p;
+    }
+
+    fn apply_memory(&mut self, state: &mut State) {
+        let mut mem = self.memory.borrow_mut();
+        for (addr, data) in state.memory.drain(..) {
+            let start = addr as usize;
+            mem.write_block(addr, &data);
+            self.baseline[start..start + data.len()].copy_from_slice(&data);
+        }
+    }
+
+    // only the bytes the editor changed are sent, so the machine's own writes next to them survive
+    fn send_changes(&mut self) {
+        let changes = changed_ranges(self.memory.borrow().slice(0, memory::MEM_SIZE), &self.baseline);
+        for (addr, data) in changes {
+            let start = addr as usize;
+            self.baseline[start..start + data.len()].copy_from_slice(&data);
+            self.send(Command::WriteMemory(addr, data));
+        }
+    }
+}
+
+// the runs of bytes that differ from before, all of it when there is no before yet
+fn changed_ranges(current: &[u8], before: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    if before.len() != current.len() {
+        return vec![(0, current.to_vec())];
+    }
+
+    let mut changes = Vec::new();
+    let chunks = current.chunks(256).zip(before.chunks(256));
+    for (n, (cur, base)) in chunks.enumerate() {
+        if cur == base {
+            continue;
+        }
+        let mut i = 0;
+        while i < cur.len() {
+            if cur[i] == base[i] {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < cur.len() && cur[i] != base[i] {
+                i += 1;
+            }
+            changes.push(((n * 256 + start) as u32, cur[start..i].to_vec()));
+        }
+    }
+    changes
+}
+
+fn state(virpc: &mut virpc::Virpc, seq: u32, last_write: &Option<(u32, Option<journal::LastWrite>)>,
+            result: &Option<(u32, Result<(), String>)>, send_profile: bool, sent: &[u8]) -> State {
+    State {
+        seq,
+        running: virpc.status(),
+        recording: virpc.is_recording(),
+        tracing: virpc.is_tracing(),
+        ips: virpc.ips(),
+        breakpoints: virpc.breakpoints().clone(),
+        watchpoints: virpc.memory.borrow().watchpoints().to_vec(),
+        watch_hit: virpc.watch_hit().cloned(),
+        journal_len: virpc.journal_len(),
+        last_write: last_write.clone(),
+        result: result.clone(),
+        profiling: virpc.is_profiling(),
+        profile: if send_profile { Some(virpc.profile().cloned()) } else { None },
+        covering: virpc.is_covering(),
+        coverage: virpc.coverage(),
+        p: virpc.status_flags(),
+        memory: changed_ranges(virpc.memory.borrow().slice(0, memory::MEM_SIZE), sent),
+    }
+}
+
+// the memory as the editor has it once it took the state that was just sent
+fn sent(virpc: &virpc::Virpc, sent: &mut Vec<u8>) {
+    sent.clear();
+    sent.extend_from_slice(virpc.memory.borrow().slice(0, memory::MEM_SIZE));
+}
+
+fn machine_thread<F>(setup: F, commands: mpsc::Receiver<(u32, Command)>, states: mpsc::SyncSender<State>,
+                    frames: Option<mpsc::SyncSender<Vec<u32>>>) -> io::Result<()>
+    where F: FnOnce() -> virpc::Virpc {
+    let mut virpc = setup();
+    if let Some(frames) = frames {
+        virpc.send_frames(frames);
+    }
+    virpc.reset();
+    virpc.run();
+
+    let mut seq = 0;
+    let mut last_write = None;
+    let mut result = None;
+    let mut send_profile = false;
+    let mut state_clock = clock::Clock::new(STATE_FREQ);
+    let mut sent_memory = Vec::new();
+    if states.send(state(&mut virpc, seq, &last_write, &result, false, &sent_memory)).is_ok() {
+        sent(&virpc, &mut sent_memory);
+    }
+
+    loop {
+        let mut handled = false;
+        loop {
+            let command = match commands.try_recv() {
+                Ok((n, command)) => { seq = n; command }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+            };
+            handled = true;
+
+            match command {
+                Command::Run => virpc.continue_cpu(),
+                Command::Stop => virpc.stop(),
+                Command::Step => virpc.step(),
+                Command::StepOver => virpc.step_over(),
+                Command::StepOut => {
+                    let stepped = if virpc.step_out() { Ok(()) } else { Err("not in a call".to_string()) };
+                    result = Some((seq, stepped));
+                }
+                Command::RunTo(adr) => virpc.run_to(adr),
+                Command::RunInstructions(count) => virpc.run_instructions(count),
+                Command::StepBack => { virpc.step_back(); }
+                Command::RunBack => { virpc.run_back(); }
+                Command::WhoWrote(adr) => last_write = Some((adr, virpc.last_write(adr))),
+                Command::Reset => virpc.reset(),
+                Command::ToggleBreakpoint(adr) => virpc.breakpoints().toggle(adr),
+                Command::RemoveBreakpoint(adr) => virpc.breakpoints().remove(adr),
+                Command::EnableBreakpoint(adr, enabled) => virpc.breakpoints().enable(adr, enabled),
+                Command::BreakpointCondition(adr, condition) => { let _ = virpc.breakpoints().set_condition(adr, &condition); }
+                Command::Watch(adr, size, watch) => virpc.memory.borrow_mut().watch(adr, size, watch),
+                Command::Unwatch(adr) => virpc.memory.borrow_mut().unwatch(adr),
+                Command::WriteMemory(addr, data) => virpc.memory.borrow_mut().write_block(addr, &data),
+                Command::Screenshot(filename) => {
+                    let saved = virpc.screenshot(&filename);
+                    result = Some((seq, saved.map_err(|why| why.to_string())));
+                }
+                Command::SaveSnapshot(filename, labels) => {
+                    let saved = virpc.save_snapshot(&filename, &labels);
+                    result = Some((seq, saved.map_err(|why| why.to_string())));
+                }
+                Command::LoadSnapshot(snap) => {
+                    let loaded = virpc.load_snapshot(&snap);
+                    result = Some((seq, loaded.map_err(|why| why.to_string())));
+                }
+                Command::StartRecording(prefix, every) => virpc.start_recording(&prefix, every),
+                Command::StopRecording => { virpc.stop_recording(); }
+                Command::StartTrace(filename) => {
+                    let format = trace::Format::from_filename(&filename);
+                    let started = fs::File::create(&filename)
+                        .and_then(|file| trace::Tracer::new(file, format, trace::Filter::new()))
+                        .map(|mut tracer| {
+                            tracer.set_labels(virpc.labels().to_vec());
+                            virpc.start_trace(tracer);
+                        });
+                    result = Some((seq, started.map_err(|why| why.to_string())));
+                }
+                Command::StopTrace => virpc.stop_trace(),
+                Command::Profile(profiling) => virpc.set_profiling(profiling),
+                Command::ClearProfile => virpc.clear_profile(),
+                Command::RequestProfile => send_profile = true,
+                Command::Coverage(covering) => virpc.set_coverage(covering),
+                Command::ClearCoverage => virpc.clear_coverage(),
+                Command::Labels(labels) => virpc.set_labels(labels),
+                Command::Quit(filename) => {
+                    virpc.stop_trace();
+                    if filename.is_empty() {
+                        return Ok(());
+                    }
+                    return virpc.screenshot(&filename).map_err(|why| {
+                        io::Error::new(why.kind(), format!("Couldn't save {}: {}", filename, why))
+                    });
+                }
+            }
+        }
+
+        if virpc.poll_gdb() {
+            handled = true;
+        }
+        if virpc.poll_dap() {
+            handled = true;
+        }
+
+        virpc.run_timed();
+
+        // a busy editor misses states instead of holding up the machine, their memory changes
+        // go with the next one
+        if (handled || state_clock.tick())
+            && states.try_send(state(&mut virpc, seq, &last_write, &result, send_profile, &sent_memory)).is_ok() {
+            sent(&virpc, &mut sent_memory);
+            send_profile = false;
+        }
+
+        thread::sleep(std::time::Duration::from_millis(TICK_MS));
+    }
+}
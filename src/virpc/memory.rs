@@ -2,6 +2,10 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 //use crate::utils;
+use crate::virpc::cpu;
+use crate::virpc::snapshot;
+use crate::virpc::PC_REG;
+use std::io;
 
 pub type MemShared = Rc<RefCell<Memory>>;
 
@@ -12,7 +16,8 @@ pub enum MemType {
     //Io,
 }
 
-const MEM_SIZE: usize = 0x080000;
+pub const MEM_SIZE: usize = 0x080000;
+pub const CODE_PAGE: u32 = 16; // bytes of the code area whose decoded instructions are dropped together
 
 // specific memory bank - RAM, ROM, IO
 pub struct MemBank {
@@ -45,13 +50,6 @@ impl MemBank {
         mem_bank
     }
 
-    pub fn write(&mut self, addr: u32, val: u8) {
-        match self.bank_type {
-            MemType::Ram => self.data[(addr - self.offset) as usize] = val,
-            MemType::Void => {},
-        }
-    }
-
     pub fn read(&mut self, addr: u32) -> u8 {
         match self.bank_type {
             MemType::Ram => self.data[(addr - self.offset) as usize],
@@ -61,10 +59,62 @@ impl MemBank {
 }
 
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum Watch {
+    Read,
+    Write,
+    Change, // only writes that change the value
+}
+
+// watched address range, checked on every byte and word access through the accessors below
+#[derive(Clone, PartialEq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub size: u32,
+    pub watch: Watch,
+    pub hits: u32,
+}
+
+// the first access that hit a watchpoint, until it is taken
+#[derive(Clone, PartialEq)]
+pub struct WatchHit {
+    pub address: u32, // of the access
+    pub watch: Watch, // Read or Write
+    pub pc: u32,      // instruction that made the access, filled in by the Virpc
+    pub old: u32,
+    pub new: u32,     // the same as old for reads
+}
+
+// a byte as it was before and after a write, logged for the journal
+#[derive(Clone, Copy, PartialEq)]
+pub struct LoggedWrite {
+    pub address: u32,
+    pub old: u8,
+    pub new: u8,
+}
+
+// a read by an instruction, logged for the trace
+#[derive(Clone, Copy, PartialEq)]
+pub struct LoggedRead {
+    pub address: u32,
+    pub size: u32, // 1 or 4 bytes
+    pub value: u32,
+}
+
 // collective memory storage with all the banks and bank switching support
 pub struct Memory {
     ram:     MemBank,
     void:    MemBank,
+    code_version: u32,    // changes on every write below cpu::BSS, for listings of the code area
+    code_pages: Vec<u32>, // the version of each page of the code area, so its decoded instructions can be dropped
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    logging: bool,         // log the byte and word writes below
+    log: Vec<LoggedWrite>,
+    logging_reads: bool,   // log the byte and word reads as well
+    reads: Vec<LoggedRead>,
+    observed: bool,        // reads are logged or watched, the one check on the read path
+    cpu_running: bool,     // only the accesses of the cpu's instructions count for watchpoints
 }
 
 impl Memory {
@@ -72,18 +122,19 @@ impl Memory {
         Rc::new(RefCell::new(Memory {
             ram:     MemBank::new(MemType::Ram),     // MEM_SIZE
             void:     MemBank::new(MemType::Void),     // Void
+            code_version: 1,
+            code_pages: vec![1; (cpu::BSS / CODE_PAGE) as usize],
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            logging: false,
+            log: Vec::new(),
+            logging_reads: false,
+            reads: Vec::new(),
+            observed: false,
+            cpu_running: false,
         }))
     }
     
-    // returns memory bank for current latch setting and address
-    pub fn get_bank(&mut self, addr: u32) -> &mut MemBank {
-        const RAMSIZE: u32 = (MEM_SIZE-1) as u32;
-        match addr {
-            0x0000..=RAMSIZE => &mut self.ram,
-            _                => &mut self.void,
-        }
-    }
-
     // returns specific modifiable memory bank
     pub fn get_ram_bank(&mut self, bank_type: MemType) -> &mut MemBank {
         match bank_type {
@@ -96,48 +147,308 @@ impl Memory {
         // enable kernal, chargen and basic ROMs
     }
 
+    // the accessors below go straight to the RAM slice, everything beyond RAM is the void:
+    // it reads as 0 and ignores writes, also for the bytes of a word that cross the end of RAM
+
     // Write a byte to memory - returns whether RAM was written (true) or RAM under ROM (false)
     pub fn write_byte(&mut self, addr: u32, value: u8) -> bool {
-        self.get_bank(addr).write(addr, value);
-        return true;
+        self.code_written(addr, 1);
+        if self.logging || !self.watchpoints.is_empty() {
+            let old = self.byte(addr);
+            self.watched(addr, 1, Watch::Write, old as u32, value as u32);
+            self.logged(addr, &[old], &[value]);
+        }
+        self.set_byte(addr, value);
+        true
     }
     
     // Read a byte from memory
     pub fn read_byte(&mut self, addr: u32) -> u8 {
-        self.get_bank(addr).read(addr)
+        let value = self.byte(addr);
+        if self.observed {
+            self.observed_read(addr, 1, value as u32);
+        }
+        value
     }
 
-    // Read a word from memory (stored in little endian)
+    // Read a word from memory (stored in little endian), aligned or not
     pub fn read_int_le(&mut self, addr: u32) -> u32 {
-        let bank = self.get_bank(addr);
-        let value_be: u32 = ((bank.read(addr) as u32) << 24 & 0xFF000000) |
-                            ((bank.read(addr + 0x0001) as u32) << 16 & 0x00FF0000) |
-                            ((bank.read(addr + 0x0002) as u32) << 8 & 0x0000FF00) |
-                            ((bank.read(addr + 0x0003) as u32) & 0x000000FF);
-
-        let value_le: u32 = ((value_be << 24) & 0xFF000000) | 
-                            ((value_be << 8) & 0x00FF0000) | 
-                            ((value_be >> 8) & 0x0000FF00) | 
-                            ((value_be >> 24) & 0x000000FF);
-        value_le
+        let value = self.int(addr);
+        if self.observed {
+            self.observed_read(addr, 4, value);
+        }
+        value
     }
 
-    // Read a word from memory (stored in little endian)
+    // Write a word to memory (stored in little endian), aligned or not
     pub fn write_int_le(&mut self, addr: u32, value: u32) -> bool {
-        let bank = self.get_bank(addr);
+        self.code_written(addr, 4);
+        if self.logging || !self.watchpoints.is_empty() {
+            let old = self.int(addr);
+            self.watched(addr, 4, Watch::Write, old, value);
+            self.logged(addr, &old.to_le_bytes(), &value.to_le_bytes());
+        }
+        self.set_int(addr, value);
+        true
+    }
 
-        let value_be: u32 = ((value << 24) & 0xFF000000) | 
-                            ((value << 8) & 0x00FF0000) | 
-                            ((value >> 8) & 0x0000FF00) | 
-                            ((value >> 24) & 0x000000FF);
+    // read without logging or watching, for the machine's own bookkeeping
+    pub fn peek_byte(&self, addr: u32) -> u8 {
+        self.byte(addr)
+    }
 
-        bank.write(addr,(value_be >> 24 & 0x000000FF) as u8);
-        bank.write(addr + 0x0001,(value_be >> 16 & 0x000000FF) as u8);
-        bank.write(addr + 0x0002,(value_be >> 8 & 0x000000FF) as u8);
-        bank.write(addr + 0x0003,(value_be & 0x000000FF) as u8);
-        true
+    pub fn peek_int_le(&self, addr: u32) -> u32 {
+        self.int(addr)
+    }
+
+    // write without logging or watching, for the pc register that moves on every fetch
+    pub fn poke_int_le(&mut self, addr: u32, value: u32) {
+        self.code_written(addr, 4);
+        self.set_int(addr, value);
+    }
+
+    // direct view of a block of RAM, for peripherals that read a lot at once.
+    // the block is cut short where RAM ends, callers treat the missing bytes as 0
+    pub fn slice(&self, addr: u32, len: usize) -> &[u8] {
+        let start = (addr as usize).min(MEM_SIZE);
+        let end = start.saturating_add(len).min(MEM_SIZE);
+        &self.ram.data[start..end]
+    }
+
+    // copy a block into RAM in one go, the part beyond RAM is dropped like writes to the void
+    pub fn write_block(&mut self, addr: u32, data: &[u8]) {
+        self.code_written(addr, data.len() as u32);
+        let start = (addr as usize).min(MEM_SIZE);
+        let end = start.saturating_add(data.len()).min(MEM_SIZE);
+        self.ram.data[start..end].copy_from_slice(&data[..end - start]);
+    }
+
+    pub fn code_version(&self) -> u32 {
+        self.code_version
+    }
+
+    // the version of the code page holding addr, which is below cpu::BSS
+    pub fn code_page_version(&self, addr: u32) -> u32 {
+        self.code_pages[(addr.min(cpu::BSS - 1) / CODE_PAGE) as usize]
+    }
+
+    // watch a range, replacing the watchpoint that starts at the same address
+    pub fn watch(&mut self, address: u32, size: u32, watch: Watch) {
+        self.unwatch(address);
+        self.watchpoints.push(Watchpoint { address, size: size.max(1), watch, hits: 0 });
+        self.observe();
+    }
+
+    pub fn unwatch(&mut self, address: u32) {
+        self.watchpoints.retain(|w| w.address != address);
+        self.observe();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn save_watchpoints(&self, w: &mut snapshot::Writer) {
+        w.u32(self.watchpoints.len() as u32);
+        for watchpoint in self.watchpoints.iter() {
+            w.u32(watchpoint.address);
+            w.u32(watchpoint.size);
+            w.u8(watchpoint.watch as u8);
+            w.u32(watchpoint.hits);
+        }
+    }
+
+    pub fn load_watchpoints(r: &mut snapshot::Reader) -> io::Result<Vec<Watchpoint>> {
+        let mut watchpoints = Vec::new();
+        for _ in 0..r.u32()? {
+            let address = r.u32()?;
+            let size = r.u32()?;
+            let watch = match r.u8()? {
+                0 => Watch::Read,
+                1 => Watch::Write,
+                _ => Watch::Change,
+            };
+            let hits = r.u32()?;
+            watchpoints.push(Watchpoint { address, size, watch, hits });
+        }
+        Ok(watchpoints)
+    }
+
+    pub fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+        self.watchpoints = watchpoints;
+        self.observe();
+    }
+
+    pub fn is_watching(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    // set while the cpu runs an instruction, peripherals and the debugger don't hit watchpoints
+    pub fn set_cpu_running(&mut self, running: bool) {
+        self.cpu_running = running;
+        self.observe();
+    }
+
+    // the first hit since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    // start or stop logging writes, the log starts empty
+    pub fn set_logging(&mut self, logging: bool) {
+        self.logging = logging;
+        self.log.clear();
+    }
+
+    pub fn clear_log(&mut self) {
+        self.log.clear();
+    }
+
+    // the writes since the log was last drained or cleared, without taking them
+    pub fn log(&self) -> &[LoggedWrite] {
+        &self.log
+    }
+
+    // the writes since the log was last drained or cleared
+    pub fn drain_log(&mut self) -> std::vec::Drain<'_, LoggedWrite> {
+        self.log.drain(..)
+    }
+
+    // start or stop logging reads, the log starts empty
+    pub fn set_read_logging(&mut self, logging: bool) {
+        self.logging_reads = logging;
+        self.reads.clear();
+        self.observe();
+    }
+
+    pub fn clear_reads(&mut self) {
+        self.reads.clear();
+    }
+
+    pub fn reads(&self) -> &[LoggedRead] {
+        &self.reads
+    }
+
+    // put back the byte a logged write replaced, without logging or watching it
+    pub fn undo(&mut self, write: &LoggedWrite) {
+        self.code_written(write.address, 1);
+        self.set_byte(write.address, write.old);
     }
 
     // *** private functions *** //
+
+    fn byte(&self, addr: u32) -> u8 {
+        match self.ram.data.get(addr as usize) {
+            Some(byte) => *byte,
+            None => 0x0,
+        }
+    }
+
+    fn set_byte(&mut self, addr: u32, value: u8) {
+        if let Some(byte) = self.ram.data.get_mut(addr as usize) {
+            *byte = value;
+        }
+    }
+
+    fn set_int(&mut self, addr: u32, value: u32) {
+        let start = addr as usize;
+        match self.ram.data.get_mut(start..start + 4) {
+            Some(bytes) => bytes.copy_from_slice(&value.to_le_bytes()),
+            None => {
+                for (i, byte) in value.to_le_bytes().iter().enumerate() {
+                    if let Some(a) = addr.checked_add(i as u32) {
+                        self.set_byte(a, *byte);
+                    }
+                }
+            }
+        }
+    }
+
+    fn int(&self, addr: u32) -> u32 {
+        let start = addr as usize;
+        match self.ram.data.get(start..start + 4) {
+            Some(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            None => {
+                let mut value = 0;
+                for i in 0..4 {
+                    if let Some(a) = addr.checked_add(i) {
+                        value |= (self.byte(a) as u32) << (8 * i);
+                    }
+                }
+                value
+            }
+        }
+    }
+
+    // every fetch moves the pc register, the journal and the trace keep the pc of each
+    // instruction instead of its writes
+    fn logged(&mut self, addr: u32, old: &[u8], new: &[u8]) {
+        if !self.logging {
+            return;
+        }
+        for (i, (old, new)) in old.iter().zip(new).enumerate() {
+            if let Some(address) = addr.checked_add(i as u32) {
+                if address.wrapping_sub(PC_REG) >= 4 {
+                    self.log.push(LoggedWrite { address, old: *old, new: *new });
+                }
+            }
+        }
+    }
+
+    fn observe(&mut self) {
+        self.observed = self.logging_reads || (self.cpu_running && !self.watchpoints.is_empty());
+    }
+
+    // kept out of the read path, which stays small enough to be inlined
+    #[inline(never)]
+    fn observed_read(&mut self, addr: u32, size: u32, value: u32) {
+        self.watched(addr, size, Watch::Read, value, value);
+        if self.logging_reads {
+            self.reads.push(LoggedRead { address: addr, size, value });
+        }
+    }
+
+    // count the watchpoints an access of size bytes hits, and keep the first hit
+    fn watched(&mut self, addr: u32, size: u32, access: Watch, old: u32, new: u32) {
+        if !self.cpu_running || self.watchpoints.is_empty() {
+            return;
+        }
+        let end = addr.saturating_add(size);
+        for w in self.watchpoints.iter_mut() {
+            if addr >= w.address.saturating_add(w.size) || end <= w.address {
+                continue;
+            }
+            let hit = match w.watch {
+                Watch::Read => access == Watch::Read,
+                Watch::Write => access == Watch::Write,
+                Watch::Change => access == Watch::Write && old != new,
+            };
+            if hit {
+                w.hits += 1;
+                if self.watch_hit.is_none() {
+                    self.watch_hit = Some(WatchHit { address: addr, watch: access, pc: 0, old, new });
+                }
+            }
+        }
+    }
+
+    // size bytes from addr changed
+    #[inline]
+    fn code_written(&mut self, addr: u32, size: u32) {
+        if addr < cpu::BSS && size > 0 {
+            self.code_pages_written(addr, size);
+        }
+    }
+
+    // 0 is never used as a version, so it can mark an empty cache entry
+    #[inline(never)]
+    fn code_pages_written(&mut self, addr: u32, size: u32) {
+        self.code_version = self.code_version.wrapping_add(1).max(1);
+        let last = addr.saturating_add(size - 1).min(cpu::BSS - 1);
+        for page in addr / CODE_PAGE..=last / CODE_PAGE {
+            let version = &mut self.code_pages[page as usize];
+            *version = version.wrapping_add(1).max(1);
+        }
+    }
 }
 
@@ -1,66 +1,123 @@
 extern crate minifb;
 
+pub mod benchmark;
+pub mod blitter;
+pub mod breakpoints;
+pub mod calls;
+pub mod capture;
+pub mod clock;
+pub mod connection;
+pub mod coverage;
 pub mod cpu;
+pub mod dap;
+pub mod display;
+pub mod dma;
+pub mod gdb;
+pub mod headless;
+pub mod journal;
+pub mod machine;
 pub mod memory;
 pub mod opcodes;
+pub mod profiler;
+pub mod snapshot;
+pub mod trace;
 pub mod video;
 
-//mod clock;
 
-use crate::debugger;
-use minifb::*;
 use crate::utils;
+use std::io;
+use std::sync::mpsc;
 
 pub const SCREEN_WIDTH:  usize = 384; // extend 20 pixels left and right for the borders
 pub const SCREEN_HEIGHT: usize = 272; // extend 36 pixels top and down for the borders
 
-//const CLOCK_FREQ: f64 = 50.0;
+// default speed, 50 frames per second
+pub const CLOCK_FREQ: f64 = (video::CYCLES_PER_LINE as usize * video::TOTAL_LINES * 50) as f64;
 pub const PC_REG: u32 = 0xF000;
 
 pub struct Virpc {
-    pub main_window: minifb::Window,
     pub program_to_load: String,
     pub memory: memory::MemShared,
-    //clock:  clock::Clock,
+    clock:  clock::Clock,
+    clock_freq: f64,
+    ips_clock: clock::Clock,
     cpu:  cpu::CPUShared,
     video: video::VideoShared,
+    dma: dma::DmaShared,
+    blitter: blitter::BlitterShared,
 
-    debugger: Option<debugger::Debugger>,
     powered_on: bool,
     boot_complete: bool,
     cycle_count: u32,
+    instructions: u32, // executed since the last instructions per second update
+    ips: u32,
     isrunning : bool,
-    breakpoint : u32,
+    breakpoints: breakpoints::Breakpoints,
+    resumed: bool, // the first instruction after a continue doesn't stop on its breakpoint
+    calls: calls::CallStack<()>, // for step over and out
+    run_count: u64, // instructions left to run before stopping, 0 runs on
+    watch_hit: Option<memory::WatchHit>, // why the cpu stopped on a watchpoint
+    journal: journal::Journal,
+    tracer: Option<trace::Tracer>,
+    profile: Option<profiler::Profile>,
+    profiling: bool,
+    recorder: Option<capture::Recorder>,
+    gdb: Option<gdb::Server>,
+    dap: Option<dap::Server>,
+    labels: Vec<cpu::Label>, // the editor's, for the debuggers
+    frames: Option<mpsc::SyncSender<Vec<u32>>>, // to the window, none without one
 }
 
 impl Virpc {
-    pub fn new(window_scale: Scale, debugger_on: bool, prg_to_load: &str) -> Virpc {
+    pub fn new(prg_to_load: &str) -> Virpc {
         let memory = memory::Memory::new_shared();
         let cpu    = cpu::CPU::new_shared(PC_REG);
         let video  = video::Video::new_shared();
+        let dma    = dma::Dma::new_shared();
+        let blitter = blitter::Blitter::new_shared();
 
         let mut virpc = Virpc {
-            main_window: Window::new("VirPC", SCREEN_WIDTH, SCREEN_HEIGHT, WindowOptions { scale: window_scale, ..Default::default() }).unwrap(),
             program_to_load: String::from(prg_to_load),
             memory: memory.clone(), // shared system memory (RAM, ROM, IO registers)
             video: video.clone(),
-            //clock:  clock::Clock::new(CLOCK_FREQ),
+            dma: dma.clone(),
+            blitter: blitter.clone(),
+            clock:  clock::Clock::new(CLOCK_FREQ),
+            clock_freq: CLOCK_FREQ,
+            ips_clock: clock::Clock::new(1.0),
             cpu:  cpu.clone(),
-            debugger: if debugger_on { Some(debugger::Debugger::new()) } else { None },
             powered_on: false,
             boot_complete: false,
             cycle_count: 0,
+            instructions: 0,
+            ips: 0,
             isrunning : false,
-            breakpoint : 0xFFFFFFFF
+            breakpoints: breakpoints::Breakpoints::new(),
+            resumed: false,
+            calls: calls::CallStack::new(),
+            run_count: 0,
+            watch_hit: None,
+            journal: journal::Journal::new(0),
+            tracer: None,
+            profile: None,
+            profiling: false,
+            recorder: None,
+            gdb: None,
+            dap: None,
+            labels: Vec::new(),
+            frames: None,
         };
 
-        virpc.main_window.set_position(75, 20);
-
         // cyclic dependencies are not possible in Rust (yet?), so we have
         // to resort to setting references manually
         virpc.cpu.borrow_mut().set_references(memory.clone());
         virpc.video.borrow_mut().set_references(memory.clone(), cpu.clone());
+        virpc.dma.borrow_mut().set_references(memory.clone(), cpu.clone());
+        virpc.blitter.borrow_mut().set_references(memory.clone());
+        memory.borrow_mut().set_logging(virpc.journal.is_enabled());
 
+        drop(blitter);
+        drop(dma);
         drop(video);
         drop(memory);
         drop(cpu);
@@ -69,11 +126,36 @@ impl Virpc {
     }
 
     pub fn reset(&mut self) {
+        self.journal.clear();
+        self.calls.clear();
         self.memory.borrow_mut().reset();
         self.cpu.borrow_mut().reset();
+        self.dma.borrow_mut().reset();
+        self.blitter.borrow_mut().reset();
+        self.video.borrow_mut().reset();
     }
 
+    // run a single cycle
     pub fn run(&mut self) {
+        self.run_cycles(1);
+    }
+
+    // run the cycles that are due at the target speed since the last call
+    pub fn run_timed(&mut self) {
+        let max_cycles = (self.clock_freq / 10.0) as u32; // catch up at most 100 ms
+        let cycles = self.clock.periods_due(max_cycles);
+        self.run_cycles(cycles);
+    }
+
+    // run whole frames as fast as possible, without a window this is how the screen gets drawn
+    pub fn run_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            self.run_cycles(video::CYCLES_PER_LINE * video::TOTAL_LINES as u32);
+        }
+    }
+
+    // run a batch of cycles, the cpu only executes instructions while it is running
+    pub fn run_cycles(&mut self, cycles: u32) {
         if !self.powered_on {
             // $0000 is the power-on reset routine
             self.cpu.borrow_mut().set_pc(0x0000);
@@ -87,59 +169,560 @@ impl Virpc {
             }
         }
 
-        // main virpc update - use the clock to time all the operations
-        if self.cpu.borrow_mut().get_pc() == self.breakpoint {
-            self.isrunning = false;
-        }
+        for _ in 0..cycles {
+            if self.isrunning {
+                let checking = !self.resumed && self.breakpoints.is_active();
+                if checking {
+                    let pc = self.cpu.borrow_mut().get_pc();
+                    if self.breakpoints.check_temporary(pc, self.calls.len())
+                       || self.breakpoints.check(pc, &self.memory.borrow()) {
+                        self.stop();
+                    }
+                }
+                self.resumed = false;
+            }
 
-        if self.isrunning == true {
-            self.cpu.borrow_mut().update();
-        }
+            let mut entry = None;
+            let mut returned = Vec::new();
+            if self.isrunning == true {
+                // only the accesses of the instruction itself count for the watchpoints
+                let watching = self.memory.borrow().is_watching();
+                let mut pc = 0;
+                if watching || self.journal.is_enabled() || self.tracer.is_some() || self.profiling {
+                    pc = self.cpu.borrow_mut().get_pc();
+                }
+                let mut traced = false;
+                if let Some(ref mut tracer) = self.tracer {
+                    let p = self.cpu.borrow().p;
+                    traced = tracer.begin(self.cycle_count, pc, p, &mut self.memory.borrow_mut());
+                }
+                if self.journal.is_enabled() {
+                    let cpu = self.cpu.borrow();
+                    let beam = self.video.borrow().beam();
+                    entry = Some(journal::Entry::new(pc, cpu.p, cpu.is_irq_armed(), self.cycle_count, beam, self.calls.len()));
+                }
 
-        self.video.borrow_mut().update(self.cycle_count);
+                if watching {
+                    self.memory.borrow_mut().set_cpu_running(true);
+                    self.cpu.borrow_mut().update();
+                    self.memory.borrow_mut().set_cpu_running(false);
+                } else {
+                    self.cpu.borrow_mut().update();
+                }
+                returned = self.track_calls();
 
-        // update the debugger window if it exists
-        match self.debugger {
-            Some(ref mut dbg) => {
-                if self.cycle_count % 2 == 0 {
-                    dbg.render(&mut self.cpu, &mut self.memory);
+                if traced {
+                    self.trace_instruction();
+                } else if self.tracer.is_some() {
+                    // filtered out, nobody takes its reads
+                    self.memory.borrow_mut().clear_reads();
                 }
-            },
-            None => (),
+                if self.profiling {
+                    if let Some(ref mut profile) = self.profile {
+                        // every instruction takes a single cycle
+                        profile.record(pc, 1, &self.cpu.borrow(), &self.memory.borrow());
+                    }
+                }
+
+                if watching {
+                    let hit = self.memory.borrow_mut().take_watch_hit();
+                    if let Some(mut hit) = hit {
+                        hit.pc = pc;
+                        self.watch_hit = Some(hit);
+                        self.stop();
+                    }
+                }
+                if self.run_count > 0 {
+                    self.run_count -= 1;
+                    if self.run_count == 0 {
+                        self.stop();
+                    }
+                }
+                self.dma.borrow_mut().update();
+                self.blitter.borrow_mut().update();
+                self.instructions += 1;
+            }
+
+            let frame_done = self.video.borrow_mut().update(self.cycle_count);
+
+            // the journal gets all writes of the cycle, also those of the peripherals
+            if let Some(entry) = entry {
+                self.journal.record(entry, &returned, &mut self.memory.borrow_mut());
+            }
+
+            if frame_done {
+                // a failing recording (disk full, bad path) stops instead of failing every frame
+                if let Some(ref mut recorder) = self.recorder {
+                    if recorder.frame(&self.video.borrow().window_buffer, SCREEN_WIDTH, SCREEN_HEIGHT).is_err() {
+                        self.recorder = None;
+                    }
+                }
+
+                // the window lives on the main thread, a frame it hasn't taken yet is skipped
+                if let Some(ref frames) = self.frames {
+                    let _ = frames.try_send(self.video.borrow().window_buffer.clone());
+                }
+            }
+
+            self.cycle_count = self.cycle_count.wrapping_add(1);
         }
-        // redraw the screen and process input on every x cycle
-        if self.cycle_count % 20 == 0 {
-            let _ = self.main_window.update_with_buffer(&self.video.borrow_mut().window_buffer, SCREEN_WIDTH, SCREEN_HEIGHT);
+
+        // writes and reads while stopped don't belong to an instruction
+        if self.journal.is_enabled() || self.tracer.is_some() {
+            let mut mem = self.memory.borrow_mut();
+            mem.clear_log();
+            mem.clear_reads();
         }
 
-        self.cycle_count += 1;
+        if self.ips_clock.tick() {
+            self.ips = self.instructions;
+            self.instructions = 0;
+        }
+    }
+
+    // target speed in cycles per second, one instruction is executed per cycle
+    pub fn set_speed(&mut self, cycles_per_second: u32) {
+        self.clock_freq = if cycles_per_second == 0 { CLOCK_FREQ } else { cycles_per_second as f64 };
+        self.clock = clock::Clock::new(self.clock_freq);
+    }
+
+    // instructions executed during the last second
+    pub fn ips(&self) -> u32 {
+        self.ips
     }
 
     pub fn continue_cpu(&mut self) {
         self.isrunning = true;
+        self.resumed = true;
+        self.watch_hit = None;
     }
 
+    // also forgets where step over, step out, run to cursor and run n were going to stop
     pub fn stop(&mut self) {
         self.isrunning = false;
+        self.breakpoints.clear_temporary();
+        self.run_count = 0;
+    }
+
+    // execute a single instruction
+    pub fn step(&mut self) {
+        self.continue_cpu();
+        self.run();
+        self.stop();
+    }
+
+    // run until the instruction after the one at pc, so a CLL runs until its call returned.
+    // other instructions are a single step
+    pub fn step_over(&mut self) {
+        let pc = self.cpu.borrow().get_pc();
+        let instruction = self.cpu.borrow().peek_instruction(pc);
+        match instruction {
+            Some((opcodes::Op::CLL, length)) => {
+                self.breakpoints.set_temporary(pc.wrapping_add(length), self.calls.len());
+                self.continue_cpu();
+            }
+            _ => self.step(),
+        }
+    }
+
+    // run until the current call returns, false when execution isn't in a call
+    pub fn step_out(&mut self) -> bool {
+        match self.calls.last() {
+            Some(frame) => {
+                self.breakpoints.set_temporary(frame.ret, self.calls.len() - 1);
+                self.continue_cpu();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // run until execution gets to an address, at any call depth
+    pub fn run_to(&mut self, address: u32) {
+        self.breakpoints.set_temporary(address, usize::MAX);
+        self.continue_cpu();
+    }
+
+    // run this many instructions, or less when something else stops the cpu first
+    pub fn run_instructions(&mut self, count: u64) {
+        if count > 0 {
+            self.run_count = count;
+            self.continue_cpu();
+        }
+    }
+
+    // finished frames go to the window on the main thread
+    pub fn send_frames(&mut self, frames: mpsc::SyncSender<Vec<u32>>) {
+        self.frames = Some(frames);
+    }
+
+    // processor status, for the debug window
+    pub fn status_flags(&self) -> u8 {
+        self.cpu.borrow().p
     }
 
     pub fn status(&mut self) -> bool {
         self.isrunning
     }
 
-    pub fn breakpoint(&mut self, adr : u32) {
-        self.breakpoint = adr;
+    pub fn breakpoints(&mut self) -> &mut breakpoints::Breakpoints {
+        &mut self.breakpoints
+    }
+
+    // keep this many executed instructions to step back through, 0 turns the journal off
+    pub fn set_journal(&mut self, size: usize) {
+        self.journal = journal::Journal::new(size);
+        self.memory.borrow_mut().set_logging(size > 0 || self.tracer.is_some());
+    }
+
+    // instructions that can be stepped back
+    pub fn journal_len(&self) -> usize {
+        self.journal.len()
+    }
+
+    // undo the last executed instruction, returns false when the journal has nothing left.
+    // peripherals keep their internal state, only what they wrote to memory is undone
+    pub fn step_back(&mut self) -> bool {
+        self.stop();
+        self.watch_hit = None;
+        let entry = match self.journal.step_back(&mut self.memory.borrow_mut(), &mut self.calls) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let mut cpu = self.cpu.borrow_mut();
+        cpu.set_pc(entry.pc);
+        cpu.p = entry.p;
+        cpu.set_irq_armed(entry.irq_armed);
+        self.cycle_count = entry.cycle;
+        self.video.borrow_mut().set_beam(entry.beam);
+        true
+    }
+
+    // step back until an enabled breakpoint holds, returns false when the journal ran out first.
+    // going backwards doesn't count as hitting it
+    pub fn run_back(&mut self) -> bool {
+        while self.step_back() {
+            let pc = self.cpu.borrow_mut().get_pc();
+            if self.breakpoints.holds(pc, &self.memory.borrow()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // the last write to an address that is still in the journal
+    pub fn last_write(&self, address: u32) -> Option<journal::LastWrite> {
+        self.journal.last_write(address)
+    }
+
+    // the watchpoint access that stopped the cpu, until it continues
+    pub fn watch_hit(&self) -> Option<&memory::WatchHit> {
+        self.watch_hit.as_ref()
+    }
+
+    // save the whole machine, with the editor's labels, to continue from later with load_snapshot
+    pub fn save_snapshot(&self, filename: &str, labels: &[cpu::Label]) -> io::Result<()> {
+        let mut snap = snapshot::Snapshot::new();
+
+        let mut w = snapshot::Writer::new();
+        w.u32(self.cycle_count);
+        snap.add(b"MACH", w);
+
+        let mut w = snapshot::Writer::new();
+        w.bytes(self.memory.borrow().slice(0, memory::MEM_SIZE));
+        snap.add(b"RAM ", w);
+
+        let mut w = snapshot::Writer::new();
+        self.cpu.borrow().save(&mut w);
+        snap.add(b"CPU ", w);
+
+        let mut w = snapshot::Writer::new();
+        self.dma.borrow().save(&mut w);
+        snap.add(b"DMA ", w);
+
+        let mut w = snapshot::Writer::new();
+        self.blitter.borrow().save(&mut w);
+        snap.add(b"BLIT", w);
+
+        let mut w = snapshot::Writer::new();
+        self.video.borrow().save(&mut w);
+        snap.add(b"VID ", w);
+
+        let mut w = snapshot::Writer::new();
+        self.breakpoints.save(&mut w);
+        snap.add(b"BRKP", w);
+
+        let mut w = snapshot::Writer::new();
+        self.memory.borrow().save_watchpoints(&mut w);
+        snap.add(b"WTCH", w);
+
+        snap.add(b"LABL", snapshot::write_labels(labels));
+        snap.save(filename)
+    }
+
+    // continue from a saved machine, stopped. Sections missing from the file are left as they are,
+    // the labels are the editor's
+    pub fn load_snapshot(&mut self, snap: &snapshot::Snapshot) -> io::Result<()> {
+        // every section is read before anything changes, so a broken one leaves the machine as it was
+        let cycle_count = match snap.section(b"MACH") {
+            Some(mut r) => Some(r.u32()?),
+            None => None,
+        };
+        let ram = match snap.section(b"RAM ") {
+            Some(mut r) => Some(r.bytes()?.to_vec()),
+            None => None,
+        };
+        let dma = match snap.section(b"DMA ") {
+            Some(mut r) => {
+                let dma = dma::Dma::new_shared();
+                dma.borrow_mut().load(&mut r)?;
+                Some(dma)
+            }
+            None => None,
+        };
+        let blitter = match snap.section(b"BLIT") {
+            Some(mut r) => {
+                let blitter = blitter::Blitter::new_shared();
+                blitter.borrow_mut().load(&mut r)?;
+                Some(blitter)
+            }
+            None => None,
+        };
+        let beam = match snap.section(b"VID ") {
+            Some(mut r) => {
+                let video = video::Video::new_shared();
+                video.borrow_mut().load(&mut r)?;
+                let beam = video.borrow().beam();
+                Some(beam)
+            }
+            None => None,
+        };
+        let breakpoints = match snap.section(b"BRKP") {
+            Some(mut r) => Some(breakpoints::Breakpoints::load(&mut r)?),
+            None => None,
+        };
+        let watchpoints = match snap.section(b"WTCH") {
+            Some(mut r) => Some(memory::Memory::load_watchpoints(&mut r)?),
+            None => None,
+        };
+        // the cpu loads all or nothing, the last one that can fail
+        if let Some(mut r) = snap.section(b"CPU ") {
+            self.cpu.borrow_mut().load(&mut r)?;
+        }
+
+        self.stop();
+        self.calls.clear();
+        self.powered_on = true; // don't load the program over it
+        self.watch_hit = None;
+        self.journal.clear();
+        if let Some(cycle_count) = cycle_count {
+            self.cycle_count = cycle_count;
+        }
+        if let Some(ram) = ram {
+            self.memory.borrow_mut().write_block(0, &ram);
+        }
+        if let Some(dma) = dma {
+            self.dma.swap(&dma);
+            self.dma.borrow_mut().set_references(self.memory.clone(), self.cpu.clone());
+        }
+        if let Some(blitter) = blitter {
+            self.blitter.swap(&blitter);
+            self.blitter.borrow_mut().set_references(self.memory.clone());
+        }
+        if let Some(beam) = beam {
+            self.video.borrow_mut().set_beam(beam);
+        }
+        if let Some(breakpoints) = breakpoints {
+            self.breakpoints = breakpoints;
+        }
+        if let Some(watchpoints) = watchpoints {
+            self.memory.borrow_mut().set_watchpoints(watchpoints);
+        }
+        Ok(())
+    }
+
+    // save the screen as it is now, without window scaling, as .png or .ppm
+    pub fn screenshot(&self, filename: &str) -> io::Result<()> {
+        capture::save_image(filename, &self.video.borrow().window_buffer, SCREEN_WIDTH, SCREEN_HEIGHT)
+    }
+
+    // write a record for every executed instruction that passes the filter, until stop_trace
+    pub fn start_trace(&mut self, tracer: trace::Tracer) {
+        self.stop_trace();
+        let mut mem = self.memory.borrow_mut();
+        mem.set_logging(true);
+        mem.set_read_logging(true);
+        self.tracer = Some(tracer);
+    }
+
+    pub fn stop_trace(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            let _ = tracer.finish();
+            let mut mem = self.memory.borrow_mut();
+            mem.set_logging(self.journal.is_enabled());
+            mem.set_read_logging(false);
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    // count executions per address and calls per function, a new profile starts empty
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profiling = profiling;
+        match self.profile {
+            Some(ref mut profile) => profile.restart_stack(),
+            None if profiling => self.profile = Some(profiler::Profile::new()),
+            None => (),
+        }
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profiling
+    }
+
+    pub fn clear_profile(&mut self) {
+        self.profile = if self.profiling { Some(profiler::Profile::new()) } else { None };
+    }
+
+    // also after profiling stopped, until it is cleared
+    pub fn profile(&self) -> Option<&profiler::Profile> {
+        self.profile.as_ref()
+    }
+
+    // which instructions ran and which way the conditional jumps went
+    pub fn set_coverage(&mut self, covering: bool) {
+        self.cpu.borrow_mut().set_coverage(covering);
+    }
+
+    pub fn is_covering(&self) -> bool {
+        self.cpu.borrow().is_covering()
+    }
+
+    pub fn clear_coverage(&mut self) {
+        self.cpu.borrow_mut().clear_coverage();
+    }
+
+    // a copy, also after covering stopped until it is cleared
+    pub fn coverage(&self) -> Option<coverage::Coverage> {
+        self.cpu.borrow().coverage().cloned()
+    }
+
+    // save every nth completed frame as prefix00000.png, prefix00001.png, ...
+    // a prefix ending in .ppm or .png picks the format
+    pub fn start_recording(&mut self, prefix: &str, every: u32) {
+        self.recorder = Some(capture::Recorder::new(prefix, every));
+    }
+
+    // returns the number of frames saved
+    pub fn stop_recording(&mut self) -> u32 {
+        match self.recorder.take() {
+            Some(recorder) => recorder.saved(),
+            None => 0,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // let gdb connect to the server's port
+    pub fn start_gdb(&mut self, server: gdb::Server) {
+        self.gdb = Some(server);
+    }
+
+    // answer gdb, returns true when it changed the machine
+    pub fn poll_gdb(&mut self) -> bool {
+        match self.gdb.take() {
+            Some(mut server) => {
+                let changed = server.poll(self);
+                self.gdb = Some(server);
+                changed
+            }
+            None => false,
+        }
+    }
+
+    // let a debug adapter protocol client connect to the server's port
+    pub fn start_dap(&mut self, server: dap::Server) {
+        self.dap = Some(server);
+    }
+
+    // answer the debug adapter client, returns true when it changed the machine
+    pub fn poll_dap(&mut self) -> bool {
+        match self.dap.take() {
+            Some(mut server) => {
+                let changed = server.poll(self);
+                self.dap = Some(server);
+                changed
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_labels(&mut self, labels: Vec<cpu::Label>) {
+        self.labels = labels;
+    }
+
+    pub fn labels(&self) -> &[cpu::Label] {
+        &self.labels
     }
 
     // *** private functions *** //
+
+    // take off the calls that returned to the new pc, and remember where the call that just
+    // started continues: the position a CLL stored, or the pc an interrupt was taken at. the
+    // handler's first instruction may be a CLL as well. returns where the returned calls went
+    fn track_calls(&mut self) -> Vec<u32> {
+        let mut returned = Vec::new();
+        if !self.calls.is_empty() {
+            let pc = self.cpu.borrow_mut().get_pc();
+            returned.extend(self.calls.returned(pc).map(|f| f.ret));
+        }
+        let cpu = self.cpu.borrow();
+        let mem = self.memory.borrow();
+        if cpu.entered_interrupt() {
+            self.calls.push(mem.peek_int_le(cpu::IRQ_RETURN), ());
+        }
+        if let opcodes::Op::CLL = cpu.instruction.opcode {
+            self.calls.push(mem.peek_int_le(cpu.instruction.arg[2]), ());
+        }
+        returned
+    }
+
+    // write the record of the instruction that just ran. without a journal nobody else takes
+    // the logged writes. a failing trace (disk full) or one with all its records stops
+    fn trace_instruction(&mut self) {
+        let done = match self.tracer {
+            Some(ref mut tracer) => {
+                let mut mem = self.memory.borrow_mut();
+                let written = tracer.end(&self.cpu.borrow(), &mem);
+                if !self.journal.is_enabled() {
+                    mem.clear_log();
+                }
+                written.is_err() || tracer.is_done()
+            }
+            None => false,
+        };
+        if done {
+            self.stop_trace();
+        }
+    }
+
     // load a *.prg file
     fn load_prg(&mut self, filename: &str) {
-        let prg_data = utils::open_file(filename, 0);
-        let start_address: u32 = ((prg_data[1] as u32) << 8) | (prg_data[0] as u32);
-        //println!("Loading {} to start location at ${:04x} ({})", filename, start_address, start_address);
+        load_prg(&self.memory, filename);
+    }
+}
 
-        for i in 2..(prg_data.len()) {
-            self.memory.borrow_mut().write_byte(start_address + (i as u32) - 2, prg_data[i]);
-        }
+// load a *.prg file into memory, the first two bytes are the start address
+pub fn load_prg(memory: &memory::MemShared, filename: &str) {
+    let prg_data = utils::open_file(filename, 0);
+    let start_address: u32 = ((prg_data[1] as u32) << 8) | (prg_data[0] as u32);
+    //println!("Loading {} to start location at ${:04x} ({})", filename, start_address, start_address);
+
+    for i in 2..(prg_data.len()) {
+        memory.borrow_mut().write_byte(start_address + (i as u32) - 2, prg_data[i]);
     }
 }
@@ -77,6 +77,8 @@ impl fmt::Display for Instruction {
 pub fn run(cpu: &mut cpu::CPU) -> bool {
     match cpu.instruction.opcode {
         Op::JMP => { 
+            // a jump through [IRQ_RETURN] returns from the interrupt handler
+            let returning = cpu.instruction.args & 0x04 > 0 && cpu.is_interrupt_return();
             match cpu.instruction.arg[1] {
                 
                 1 => { if cpu.get_status_flag(cpu::StatusFlag::Carry) { cpu.set_pc(cpu.instruction.arg[0]); } },
@@ -102,6 +104,9 @@ pub fn run(cpu: &mut cpu::CPU) -> bool {
                 17 => { cpu.set_pc((cpu.prev_pc as i32 + cpu.instruction.arg[0] as i32) as u32); },
                 _ => cpu.set_pc(cpu.instruction.arg[0]),
             }
+            if returning && cpu.get_pc() == cpu.instruction.arg[0] {
+                cpu.restore_interrupt_flags();
+            }
         },
         Op::CLL => {
             let pos = cpu.get_pc();
@@ -0,0 +1,214 @@
+// execution profile: executions and cycles per instruction address, and a call tree.
+// functions start at CLL targets (and the interrupt handler), a function returns when execution
+// reaches the position its CLL stored. the editor names them after its labels
+use std::collections::HashMap;
+use crate::virpc::calls;
+use crate::virpc::cpu;
+use crate::virpc::memory;
+use crate::virpc::opcodes;
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Hits {
+    pub executions: u64,
+    pub cycles: u64,
+}
+
+impl Hits {
+    fn add(&mut self, other: Hits) {
+        self.executions += other.executions;
+        self.cycles += other.cycles;
+    }
+}
+
+// a function with the time spent in it, over all the places it was called from
+#[derive(Clone, Copy, Default)]
+pub struct Function {
+    pub address: u32,
+    pub calls: u64,
+    pub own: Hits,   // in the function itself
+    pub total: Hits, // including the functions it called
+}
+
+// a function as called along one path from the root
+#[derive(Clone)]
+struct Node {
+    function: u32,
+    parent: usize, // the root is its own parent
+    calls: u64,
+    hits: Hits,
+    children: Vec<usize>,
+}
+
+#[derive(Clone, Default)]
+pub struct Profile {
+    addresses: HashMap<u32, Hits>,
+    nodes: Vec<Node>,   // node 0 is the function running when profiling started
+    frames: calls::CallStack<usize>, // the nodes of the calls that haven't returned yet
+    current: usize,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    // count the instruction that ran from pc, call this after the cpu update
+    pub fn record(&mut self, pc: u32, cycles: u32, cpu: &cpu::CPU, mem: &memory::Memory) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node { function: pc, parent: 0, calls: 1, hits: Hits::default(), children: Vec::new() });
+        }
+
+        // back in a caller, also when functions in between didn't return the regular way
+        if let Some(frame) = self.frames.returned(pc).next() {
+            self.current = self.nodes[frame.info].parent;
+        }
+
+        // the handler's first instruction ran instead of the one at pc
+        let mut pc = pc;
+        if cpu.entered_interrupt() {
+            let vector = mem.peek_int_le(cpu::IRQ_VECTOR);
+            self.call(vector, pc);
+            pc = vector;
+        }
+
+        let hits = Hits { executions: 1, cycles: cycles as u64 };
+        self.addresses.entry(pc).or_default().add(hits);
+        self.nodes[self.current].hits.add(hits);
+
+        // the running cpu keeps its pc in memory, which is borrowed here already
+        if let opcodes::Op::CLL = cpu.instruction.opcode {
+            let ret = mem.peek_int_le(cpu.instruction.arg[2]);
+            self.call(mem.peek_int_le(cpu.pc_reg), ret);
+        }
+    }
+
+    // forget the calls in progress, after the program ran without being profiled
+    pub fn restart_stack(&mut self) {
+        self.frames.clear();
+        self.current = 0;
+    }
+
+    pub fn hits(&self, address: u32) -> Hits {
+        self.addresses.get(&address).copied().unwrap_or_default()
+    }
+
+    // the most executed instruction, for scaling
+    pub fn max_executions(&self) -> u64 {
+        self.addresses.values().map(|h| h.executions).max().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> Hits {
+        let mut total = Hits::default();
+        for node in self.nodes.iter() {
+            total.add(node.hits);
+        }
+        total
+    }
+
+    // all functions, the most expensive ones (own cycles) first
+    pub fn functions(&self) -> Vec<Function> {
+        // children come after their parent, so the subtree totals add up from the back
+        let mut subtree: Vec<Hits> = self.nodes.iter().map(|n| n.hits).collect();
+        for i in (1..self.nodes.len()).rev() {
+            let hits = subtree[i];
+            subtree[self.nodes[i].parent].add(hits);
+        }
+
+        let mut functions: HashMap<u32, Function> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let function = functions.entry(node.function)
+                .or_insert(Function { address: node.function, ..Function::default() });
+            function.calls += node.calls;
+            function.own.add(node.hits);
+            // a recursive call is already in the total of the outer one
+            if !self.is_recursion(i) {
+                function.total.add(subtree[i]);
+            }
+        }
+        let mut functions: Vec<Function> = functions.into_values().collect();
+        functions.sort_by(|a, b| b.own.cycles.cmp(&a.own.cycles).then(a.address.cmp(&b.address)));
+        functions
+    }
+
+    // the functions that called function, with the number of calls, most calls first
+    pub fn callers(&self, function: u32) -> Vec<(u32, u64)> {
+        let mut callers = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i > 0 && node.function == function {
+                *callers.entry(self.nodes[node.parent].function).or_insert(0) += node.calls;
+            }
+        }
+        sorted(callers)
+    }
+
+    // the functions that function called, with the number of calls, most calls first
+    pub fn callees(&self, function: u32) -> Vec<(u32, u64)> {
+        let mut callees = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i > 0 && self.nodes[node.parent].function == function {
+                *callees.entry(node.function).or_insert(0) += node.calls;
+            }
+        }
+        sorted(callees)
+    }
+
+    // one line per call path, "root;caller;function cycles", the input of flame graph tools
+    pub fn folded<F>(&self, mut name: F) -> String
+        where F: FnMut(u32) -> String {
+        let mut folded = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.hits.cycles == 0 {
+                continue;
+            }
+            let mut path = vec![name(node.function)];
+            let mut n = i;
+            while n != 0 {
+                n = self.nodes[n].parent;
+                path.push(name(self.nodes[n].function));
+            }
+            path.reverse();
+            folded.push_str(&format!("{} {}\n", path.join(";"), node.hits.cycles));
+        }
+        folded
+    }
+
+    // *** private functions *** //
+
+    // deeper calls than the stack keeps are counted in the deepest function
+    fn call(&mut self, function: u32, ret: u32) {
+        if self.frames.is_full() {
+            return;
+        }
+        let current = self.current;
+        let child = match self.nodes[current].children.iter().find(|&&c| self.nodes[c].function == function) {
+            Some(&child) => child,
+            None => {
+                self.nodes.push(Node { function, parent: current, calls: 0, hits: Hits::default(), children: Vec::new() });
+                let child = self.nodes.len() - 1;
+                self.nodes[current].children.push(child);
+                child
+            }
+        };
+        self.nodes[child].calls += 1;
+        self.frames.push(ret, child);
+        self.current = child;
+    }
+
+    fn is_recursion(&self, node: usize) -> bool {
+        let function = self.nodes[node].function;
+        let mut n = node;
+        while n != 0 {
+            n = self.nodes[n].parent;
+            if self.nodes[n].function == function {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn sorted(counts: HashMap<u32, u64>) -> Vec<(u32, u64)> {
+    let mut counts: Vec<(u32, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
@@ -0,0 +1,179 @@
+// machine snapshot files: a header with the format version, then tagged sections (RAM, cpu,
+// peripherals, labels, breakpoints, ...), so a reader can skip the sections it doesn't know
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use crate::virpc::cpu;
+
+const MAGIC: &[u8; 8] = b"RPROCSNP";
+pub const VERSION: u32 = 1; // raise when a section changes, older files keep loading
+
+// section contents are written as little endian values, strings and blocks with their length first
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("a text that isn't utf-8"))
+    }
+
+    // fields added to a section later are missing in older files
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    // *** private functions *** //
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        match self.data.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err(invalid("a section that ends too soon")),
+        }
+    }
+}
+
+pub struct Snapshot {
+    version: u32,
+    sections: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl Snapshot {
+    pub fn new() -> Snapshot {
+        Snapshot { version: VERSION, sections: Vec::new() }
+    }
+
+    pub fn add(&mut self, tag: &[u8; 4], section: Writer) {
+        self.sections.push((*tag, section.data));
+    }
+
+    pub fn section(&self, tag: &[u8; 4]) -> Option<Reader<'_>> {
+        self.sections.iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, data)| Reader { data, pos: 0 })
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&self.version.to_le_bytes());
+        for (tag, section) in self.sections.iter() {
+            data.extend_from_slice(tag);
+            data.extend_from_slice(&(section.len() as u32).to_le_bytes());
+            data.extend_from_slice(section);
+        }
+        let mut file = File::create(filename)?;
+        file.write_all(&data)
+    }
+
+    pub fn load(filename: &str) -> io::Result<Snapshot> {
+        let mut data = Vec::new();
+        File::open(filename)?.read_to_end(&mut data)?;
+
+        let mut reader = Reader { data: &data, pos: 0 };
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(invalid("no rproc snapshot"));
+        }
+        let version = reader.u32()?;
+        if version > VERSION {
+            return Err(invalid(&format!("snapshot version {}, this rproc reads up to {}", version, VERSION)));
+        }
+
+        let mut snapshot = Snapshot { version, sections: Vec::new() };
+        while reader.pos < data.len() {
+            let tag = reader.take(4)?;
+            let section = reader.bytes()?;
+            snapshot.sections.push(([tag[0], tag[1], tag[2], tag[3]], section.to_vec()));
+        }
+        Ok(snapshot)
+    }
+}
+
+// the labels are the editor's, they are stored with the machine state
+pub fn write_labels(labels: &[cpu::Label]) -> Writer {
+    let mut w = Writer::new();
+    w.u32(labels.len() as u32);
+    for label in labels {
+        w.u32(label.address);
+        w.u32(label.size);
+        w.string(&label.tag);
+    }
+    w
+}
+
+pub fn load_labels(snapshot: &Snapshot) -> io::Result<Vec<cpu::Label>> {
+    let mut labels = Vec::new();
+    if let Some(mut r) = snapshot.section(b"LABL") {
+        for _ in 0..r.u32()? {
+            let address = r.u32()?;
+            let size = r.u32()?;
+            let tag = r.string()?;
+            labels.push(cpu::Label { address, size, tag });
+        }
+    }
+    Ok(labels)
+}
+
+pub fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
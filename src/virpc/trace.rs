@@ -0,0 +1,170 @@
+// instruction trace: one record per executed instruction, with its disassembly, operand values,
+// memory reads and writes and the flags before and after, as JSON lines or CSV.
+// two traces of the same program can be compared to find where they diverge
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use crate::utils::json;
+use crate::virpc::cpu;
+use crate::virpc::memory;
+
+const MAX_LENGTH: usize = 13; // opcode and three int operands
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json, // one object per line
+    Csv,
+}
+
+impl Format {
+    // .csv is CSV, anything else JSON lines
+    pub fn from_filename(filename: &str) -> Format {
+        if filename.to_lowercase().ends_with(".csv") { Format::Csv } else { Format::Json }
+    }
+}
+
+// which instructions get a record, so long runs don't fill the disk
+#[derive(Clone, Copy)]
+pub struct Filter {
+    pub from: u32,  // pc range, both included
+    pub to: u32,
+    pub skip: u64,  // instructions in the range to pass before the first record
+    pub count: u64, // records to write, 0 for no limit
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter { from: 0, to: u32::MAX, skip: 0, count: 0 }
+    }
+}
+
+// the part of a record that is known before the instruction runs
+struct Pending {
+    cycle: u32,
+    pc: u32,
+    text: String,
+    p: u8,
+    writes: usize, // logged writes before the instruction
+}
+
+pub struct Tracer {
+    out: BufWriter<File>,
+    format: Format,
+    filter: Filter,
+    passed: u64,  // instructions in the range so far
+    written: u64, // records
+    pending: Option<Pending>,
+    disassembler: cpu::CPUShared, // keeps its pc to itself, so it doesn't touch the machine
+    code: memory::MemShared,      // copy of the instruction being disassembled
+}
+
+impl Tracer {
+    pub fn new(file: File, format: Format, filter: Filter) -> io::Result<Tracer> {
+        let code = memory::Memory::new_shared();
+        let disassembler = cpu::CPU::new_shared(0);
+        disassembler.borrow_mut().set_references(code.clone());
+
+        let mut out = BufWriter::new(file);
+        if format == Format::Csv {
+            writeln!(out, "cycle,pc,text,operands,reads,writes,flags_before,flags_after")?;
+        }
+        Ok(Tracer { out, format, filter, passed: 0, written: 0, pending: None, disassembler, code })
+    }
+
+    // labels used in the disassembly
+    pub fn set_labels(&mut self, labels: Vec<cpu::Label>) {
+        self.disassembler.borrow_mut().labels = labels;
+    }
+
+    // all records are written
+    pub fn is_done(&self) -> bool {
+        self.filter.count > 0 && self.written >= self.filter.count
+    }
+
+    // call before the instruction at pc runs, returns whether it gets a record.
+    // the memory read log is cleared, so it only holds the reads of the instruction
+    pub fn begin(&mut self, cycle: u32, pc: u32, p: u8, mem: &mut memory::Memory) -> bool {
+        self.pending = None;
+        if pc < self.filter.from || pc > self.filter.to || self.is_done() {
+            return false;
+        }
+        self.passed += 1;
+        if self.passed <= self.filter.skip {
+            return false;
+        }
+        let text = self.disassemble(pc, mem);
+        mem.clear_reads();
+        self.pending = Some(Pending { cycle, pc, text, p, writes: mem.log().len() });
+        true
+    }
+
+    // call after the instruction ran, to write its record
+    pub fn end(&mut self, cpu: &cpu::CPU, mem: &memory::Memory) -> io::Result<()> {
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        // an interrupt was taken first, so the handler's first instruction ran instead
+        if cpu.entered_interrupt() {
+            let vector = mem.slice(cpu::IRQ_VECTOR, 4);
+            if vector.len() == 4 {
+                pending.pc = u32::from_le_bytes([vector[0], vector[1], vector[2], vector[3]]);
+                pending.text = self.disassemble(pending.pc, mem);
+            }
+        }
+
+        // fetching the instruction and moving the pc aren't logged, they aren't part of what it does
+        let reads: Vec<&memory::LoggedRead> = mem.reads().iter().collect();
+        let writes: Vec<&memory::LoggedWrite> = mem.log().get(pending.writes..).unwrap_or(&[]).iter().collect();
+        let operands = &cpu.instruction.arg[..(cpu.instruction.size as usize).min(3)];
+
+        self.written += 1;
+        match self.format {
+            Format::Json => {
+                let operands: Vec<json::Value> = operands.iter().map(|&v| v.into()).collect();
+                let reads: Vec<json::Value> = reads.iter()
+                    .map(|r| json::object(vec![("address", r.address.into()), ("size", r.size.into()), ("value", r.value.into())]))
+                    .collect();
+                let writes: Vec<json::Value> = writes.iter()
+                    .map(|w| json::object(vec![("address", w.address.into()), ("old", u32::from(w.old).into()), ("new", u32::from(w.new).into())]))
+                    .collect();
+                let record = json::object(vec![
+                    ("cycle", pending.cycle.into()),
+                    ("pc", pending.pc.into()),
+                    ("text", pending.text.into()),
+                    ("operands", operands.into()),
+                    ("reads", reads.into()),
+                    ("writes", writes.into()),
+                    ("flags_before", u32::from(pending.p).into()),
+                    ("flags_after", u32::from(cpu.p).into()),
+                ]);
+                writeln!(self.out, "{}", record)
+            }
+            Format::Csv => {
+                // lists are space separated, reads as address:value, writes as address:old>new
+                let operands: Vec<String> = operands.iter().map(|v| v.to_string()).collect();
+                let reads: Vec<String> = reads.iter().map(|r| format!("{}:{}", r.address, r.value)).collect();
+                let writes: Vec<String> = writes.iter().map(|w| format!("{}:{}>{}", w.address, w.old, w.new)).collect();
+                writeln!(self.out, "{},{},\"{}\",{},{},{},{},{}",
+                    pending.cycle, pending.pc, pending.text.replace('"', "\"\""), operands.join(" "),
+                    reads.join(" "), writes.join(" "), pending.p, cpu.p)
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    // *** private functions *** //
+
+    // the text of the instruction at pc, from a copy of its bytes so nothing is logged or watched
+    fn disassemble(&mut self, pc: u32, mem: &memory::Memory) -> String {
+        self.code.borrow_mut().write_block(pc, mem.slice(pc, MAX_LENGTH));
+        let mut disassembler = self.disassembler.borrow_mut();
+        disassembler.load_opcode_data(pc);
+        let text = disassembler.instruction_to_text();
+        text.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+}
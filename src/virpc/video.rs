@@ -1,39 +1,197 @@
 use crate::virpc;
+use crate::debugger::font;
 use crate::virpc::memory;
 use crate::virpc::cpu;
+use crate::virpc::snapshot;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::io;
 use crate::utils;
 
 
 pub type VideoShared = Rc<RefCell<Video>>;
 
+// video registers
+pub const VIDEO_BASE: u32 = 0xF400;     // framebuffer start address, takes effect at the next vertical blank (int)
+pub const VIDEO_MODE: u32 = 0xF404;     // display mode, see MODE_ (byte)
+pub const VIDEO_BORDER: u32 = 0xF405;   // border colour index (byte)
+pub const VIDEO_CONTROL: u32 = 0xF406;  // bit 0: palette cycling (byte)
+pub const VIDEO_SCROLL_X: u32 = 0xF408; // horizontal scroll in pixels, wraps around (int)
+pub const VIDEO_SCROLL_Y: u32 = 0xF40C; // vertical scroll in pixels, wraps around (int)
+pub const VIDEO_TEXT_BASE: u32 = 0xF410;  // character matrix, one character code per cell (int)
+pub const VIDEO_TEXT_COLOR: u32 = 0xF414; // colour matrix, foreground in the low and background in the high nibble (int)
+pub const VIDEO_FONT: u32 = 0xF418;       // font, 8 bytes per character, most significant bit left (int)
+pub const VIDEO_TEXT_COLS: u32 = 0xF41C;  // characters per line, 1-48 (byte)
+pub const VIDEO_TEXT_ROWS: u32 = 0xF41D;  // lines on screen, 1-34 (byte)
+pub const VIDEO_RASTER: u32 = 0xF420;     // line the beam is drawing, 0-311 (int)
+pub const VIDEO_RASTER_IRQ: u32 = 0xF424; // line that raises the raster interrupt (int)
+pub const VIDEO_STATUS: u32 = 0xF428;     // bit 0: vertical blank (byte)
+pub const VIDEO_IRQ: u32 = 0xF429;        // bit 0: raster interrupt enable (byte)
+pub const VIDEO_FLIP: u32 = 0xF42A;       // set to 1 after changing VIDEO_BASE, cleared when the new page is shown (byte)
+pub const SPRITES: u32 = 0xF480;        // 8 sprites of 16 bytes, see the SPRITE_ offsets
+pub const SPRITE_COLLISION: u32 = 0xF500;    // sprites that touched another sprite, write 0 to clear (byte)
+pub const SPRITE_BG_COLLISION: u32 = 0xF501; // sprites that touched a non zero pixel, write 0 to clear (byte)
+pub const SPRITE_IRQ: u32 = 0xF502;          // bit 0: interrupt on sprite collision, bit 1: on background collision (byte)
+pub const VIDEO_PALETTE: u32 = 0xF600;  // 256 colours, 0x00RRGGBB (int each), 16 colour modes use the first 16
+
+// display modes, the bitmap modes are all 300x200 pixels. Mode 1 turns the display off
+pub const MODE_BITMAP16: u8 = 0;  // one byte per pixel, lower 4 bits index the palette
+pub const MODE_TEXT: u8 = 2;      // character matrix
+pub const MODE_BITMAP256: u8 = 3; // one byte per pixel, indexes the full palette
+pub const MODE_RGB565: u8 = 4;    // two bytes per pixel, 5 bits red, 6 bits green, 5 bits blue
+pub const MODE_RGB888: u8 = 5;    // four bytes per pixel, 0x00RRGGBB
+
+pub const FRAMEBUFFER: u32 = 0x10000;
+pub const TEXT_MATRIX: u32 = 0x1F000;
+pub const COLOR_MATRIX: u32 = 0x1F400;
+pub const FONT_ROM: u32 = 0x1F800;
+
+const DISPLAY_W: usize = 300;
+const DISPLAY_H: usize = 200;
+const DISPLAY_X: usize = 42; // left border width
+const DISPLAY_Y: usize = 36; // top border height
+
+// beam timing, the lines after the visible screen are the vertical blank
+pub const CYCLES_PER_LINE: u32 = 64;
+pub const TOTAL_LINES: usize = 312;
+
+// offsets within a sprite's registers
+pub const SPRITE_POINTER: u32 = 0;  // 16x16 pixels, 2 bytes per line, most significant bit left (int)
+pub const SPRITE_X: u32 = 4;        // signed position relative to the top left of the display (int)
+pub const SPRITE_Y: u32 = 8;        // (int)
+pub const SPRITE_COLOR: u32 = 12;   // colour index (byte)
+pub const SPRITE_CONTROL: u32 = 13; // bit 0: enable, bit 1: behind the background (byte)
+
+const SPRITE_COUNT: u32 = 8;
+const SPRITE_SIZE: usize = 16;
+
+const TEXT_COLS: u8 = 40;
+const TEXT_ROWS: u8 = 25;
+
+// visible part of the screen that is not border
+struct Area {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+}
+
+// where the beam is, for the journal to go back to
+#[derive(Clone, Copy)]
+pub struct Beam {
+    raster_line: usize,
+    display_base: u32,
+}
+
 pub struct Video {
     pub window_buffer: Vec<u32>,
     mem_ref: Option<memory::MemShared>,
     cpu_ref: Option<cpu::CPUShared>,
+    font_rom: Vec<u8>,
+    foreground: Vec<bool>, // pixels of the current line that are not background colour
+    raster_line: usize,    // next line the beam will draw
+    display_base: u32,     // framebuffer start latched at the last vertical blank
     //screen_chunk_offset: usize, // current offset from screen start
     //line_start_offset: usize,   // offset to the next line start on screen
 }
 
+// byte of a memory slice, past its end reads as 0 like memory beyond RAM
+fn byte_at(data: &[u8], i: usize) -> u8 {
+    data.get(i).copied().unwrap_or(0)
+}
+
+// little endian int of a memory slice
+fn int_at(data: &[u8], i: usize) -> u32 {
+    byte_at(data, i) as u32 | (byte_at(data, i + 1) as u32) << 8 | (byte_at(data, i + 2) as u32) << 16 | (byte_at(data, i + 3) as u32) << 24
+}
+
 impl Video {
     pub fn new_shared() -> VideoShared {
         Rc::new(RefCell::new(Video {
             window_buffer: vec![0; virpc::SCREEN_WIDTH * virpc::SCREEN_HEIGHT],
             mem_ref: None,
             cpu_ref: None,
+            font_rom: font::SysFont::new().ascii_rom(),
+            foreground: vec![false; virpc::SCREEN_WIDTH],
+            raster_line: 0,
+            display_base: FRAMEBUFFER,
             //screen_chunk_offset: 0,
             //line_start_offset:   0,
         }))
     }
     
+    // set the video registers to their power-on values
+    pub fn reset(&mut self) {
+        self.display_base = FRAMEBUFFER;
+        let mut mem = as_mut!(self.mem_ref);
+        mem.write_int_le(VIDEO_BASE, FRAMEBUFFER);
+        mem.write_byte(VIDEO_FLIP, 0);
+        mem.write_byte(VIDEO_MODE, 0);
+        mem.write_byte(VIDEO_BORDER, 0);
+        mem.write_byte(VIDEO_CONTROL, 0);
+        mem.write_int_le(VIDEO_SCROLL_X, 0);
+        mem.write_int_le(VIDEO_SCROLL_Y, 0);
+        mem.write_int_le(VIDEO_TEXT_BASE, TEXT_MATRIX);
+        mem.write_int_le(VIDEO_TEXT_COLOR, COLOR_MATRIX);
+        mem.write_int_le(VIDEO_FONT, FONT_ROM);
+        mem.write_byte(VIDEO_TEXT_COLS, TEXT_COLS);
+        mem.write_byte(VIDEO_TEXT_ROWS, TEXT_ROWS);
+        mem.write_int_le(VIDEO_RASTER_IRQ, 0);
+        mem.write_byte(VIDEO_IRQ, 0);
+        for (i, line) in self.font_rom.iter().enumerate() {
+            mem.write_byte(FONT_ROM + i as u32, *line);
+        }
+        for i in 0..SPRITE_COUNT {
+            mem.write_byte(SPRITES + 16 * i + SPRITE_CONTROL, 0);
+        }
+        mem.write_byte(SPRITE_COLLISION, 0);
+        mem.write_byte(SPRITE_BG_COLLISION, 0);
+        mem.write_byte(SPRITE_IRQ, 0);
+        for i in 0..256 {
+            mem.write_int_le(VIDEO_PALETTE + 4 * i, Video::default_color(i as u8));
+        }
+    }
+
+    // the beam position, the registers are saved with memory and the picture is drawn again
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.u32(self.raster_line as u32);
+        w.u32(self.display_base);
+    }
+
+    pub fn load(&mut self, r: &mut snapshot::Reader) -> io::Result<()> {
+        self.raster_line = r.u32()? as usize;
+        self.display_base = r.u32()?;
+        Ok(())
+    }
+
+    pub fn beam(&self) -> Beam {
+        Beam { raster_line: self.raster_line, display_base: self.display_base }
+    }
+
+    // put the beam back without drawing, the registers it wrote are restored with memory
+    pub fn set_beam(&mut self, beam: Beam) {
+        self.raster_line = beam.raster_line;
+        self.display_base = beam.display_base;
+    }
+
+    // move the beam to where it should be at this cycle, drawing the lines it passes.
+    // returns true when a frame was completed
     pub fn update(&mut self, c64_cycle_cnt: u32) -> bool {
-        for i in 0..(300*200) {
-            let dst_color = as_mut!(self.mem_ref).read_byte(0x10000 + i as u32);
-            let color_rgba = utils::fetch_c64_color_rgba(dst_color + (c64_cycle_cnt/100) as u8);
-            utils::memset8(&mut self.window_buffer, i + (384*36) + 42 + (84 * (i/300)) , color_rgba);
+        let target = ((c64_cycle_cnt / CYCLES_PER_LINE) % TOTAL_LINES as u32) as usize;
+        let mut frame_done = false;
+
+        while self.raster_line != target {
+            if self.raster_line < virpc::SCREEN_HEIGHT {
+                self.draw_line(self.raster_line, c64_cycle_cnt);
+            }
+            self.raster_line = (self.raster_line + 1) % TOTAL_LINES;
+            if self.raster_line == virpc::SCREEN_HEIGHT {
+                self.flip_page();
+                frame_done = true;
+            }
+            self.update_raster();
         }
-        false
+        frame_done
     }
 
     pub fn set_references(&mut self, memref: memory::MemShared, cpuref: cpu::CPUShared) {
@@ -41,6 +199,254 @@ impl Video {
         self.cpu_ref = Some(cpuref);
     }
     
+    // *** private functions *** //
+
+    // the beam is off screen, so the next frame can start from another page without tearing
+    fn flip_page(&mut self) {
+        let mut mem = as_mut!(self.mem_ref);
+        self.display_base = mem.read_int_le(VIDEO_BASE);
+        mem.write_byte(VIDEO_FLIP, 0);
+    }
+
+    // publish the beam position, and raise the raster interrupt when the programmed line is reached
+    fn update_raster(&mut self) {
+        let mut mem = as_mut!(self.mem_ref);
+        let vblank = self.raster_line >= virpc::SCREEN_HEIGHT;
+        mem.write_int_le(VIDEO_RASTER, self.raster_line as u32);
+        mem.write_byte(VIDEO_STATUS, if vblank { 0x01 } else { 0x00 });
+
+        let irq = mem.read_byte(VIDEO_IRQ) & 0x01 != 0;
+        let irq_line = mem.read_int_le(VIDEO_RASTER_IRQ) as usize;
+        drop(mem);
+
+        if irq && irq_line == self.raster_line {
+            as_mut!(self.cpu_ref).raise_interrupt(cpu::Interrupt::Raster);
+        }
+    }
+
+    // WinVICE colours, followed by a 6x6x6 colour cube and a grey ramp
+    fn default_color(idx: u8) -> u32 {
+        match idx {
+            0..=15 => utils::fetch_c64_color_rgba(idx),
+            16..=231 => {
+                let level = |v: u8| if v == 0 { 0 } else { 55 + 40 * v as u32 };
+                let i = idx - 16;
+                (level(i / 36) << 16) | (level((i / 6) % 6) << 8) | level(i % 6)
+            }
+            _ => {
+                let grey = 8 + 10 * (idx - 232) as u32;
+                (grey << 16) | (grey << 8) | grey
+            }
+        }
+    }
+
+    // draw one line of the screen with the registers as they are right now
+    fn draw_line(&mut self, y: usize, c64_cycle_cnt: u32) {
+        let mode = as_mut!(self.mem_ref).read_byte(VIDEO_MODE);
+        let entries = match mode {
+            MODE_BITMAP16 | MODE_TEXT => 16,
+            _ => 256,
+        };
+        let palette: Vec<u32> = as_ref!(self.mem_ref).slice(VIDEO_PALETTE, 4 * entries)
+            .chunks(4)
+            .map(|c| int_at(c, 0) & 0x00FFFFFF)
+            .collect();
+        let border = palette[as_mut!(self.mem_ref).read_byte(VIDEO_BORDER) as usize & (entries - 1)];
+        let area = self.display_area(mode);
+        let line = y * virpc::SCREEN_WIDTH;
+
+        if y < area.y || y >= area.y + area.h {
+            for x in 0..virpc::SCREEN_WIDTH {
+                self.window_buffer[line + x] = border;
+            }
+            return;
+        }
+
+        match mode {
+            MODE_BITMAP16 | MODE_BITMAP256 | MODE_RGB565 | MODE_RGB888 => self.draw_bitmap_line(&area, y, mode, &palette, c64_cycle_cnt),
+            MODE_TEXT => self.draw_text_line(&area, y, &palette),
+            _ => {}
+        }
+        self.draw_sprites_line(&area, y, &palette);
+
+        for x in 0..area.x {
+            self.window_buffer[line + x] = border;
+        }
+        for x in (area.x + area.w)..virpc::SCREEN_WIDTH {
+            self.window_buffer[line + x] = border;
+        }
+    }
+
+    // part of the screen used by the current mode, the rest is border
+    fn display_area(&mut self, mode: u8) -> Area {
+        match mode {
+            MODE_BITMAP16 | MODE_BITMAP256 | MODE_RGB565 | MODE_RGB888 => Area { x: DISPLAY_X, y: DISPLAY_Y, w: DISPLAY_W, h: DISPLAY_H },
+            MODE_TEXT => {
+                let (cols, rows) = self.text_size();
+                Area {
+                    x: (virpc::SCREEN_WIDTH - cols * 8) / 2,
+                    y: (virpc::SCREEN_HEIGHT - rows * 8) / 2,
+                    w: cols * 8,
+                    h: rows * 8,
+                }
+            }
+            // display off or unknown mode, all border
+            _ => Area { x: 0, y: 0, w: 0, h: 0 },
+        }
+    }
+
+    fn text_size(&mut self) -> (usize, usize) {
+        let mut mem = as_mut!(self.mem_ref);
+        let cols = match mem.read_byte(VIDEO_TEXT_COLS) as usize {
+            0 => TEXT_COLS as usize,
+            c => c.min(virpc::SCREEN_WIDTH / 8),
+        };
+        let rows = match mem.read_byte(VIDEO_TEXT_ROWS) as usize {
+            0 => TEXT_ROWS as usize,
+            r => r.min(virpc::SCREEN_HEIGHT / 8),
+        };
+        (cols, rows)
+    }
+
+    // palette index or direct colour, the framebuffer size follows from the bytes per pixel
+    fn draw_bitmap_line(&mut self, area: &Area, y: usize, mode: u8, palette: &[u32], c64_cycle_cnt: u32) {
+        let bytes_per_pixel = match mode {
+            MODE_RGB565 => 2,
+            MODE_RGB888 => 4,
+            _ => 1,
+        };
+        let base = self.display_base;
+        let mut mem = as_mut!(self.mem_ref);
+        let control = mem.read_byte(VIDEO_CONTROL);
+        let scroll_x = mem.read_int_le(VIDEO_SCROLL_X) as usize % area.w;
+        let scroll_y = mem.read_int_le(VIDEO_SCROLL_Y) as usize % area.h;
+        let cycle = if control & 0x01 != 0 { (c64_cycle_cnt / 100) as u8 } else { 0 };
+
+        // the whole source line in one go, instead of a memory access per pixel
+        let row_bytes = area.w * bytes_per_pixel;
+        let src_line = base.wrapping_add((((y - area.y + scroll_y) % area.h) * row_bytes) as u32);
+        let row = mem.slice(src_line, row_bytes);
+        let dst_line = y * virpc::SCREEN_WIDTH + area.x;
+        for x in 0..area.w {
+            let src = ((x + scroll_x) % area.w) * bytes_per_pixel;
+            let (color_rgba, foreground) = match mode {
+                MODE_RGB565 => {
+                    let v = byte_at(row, src) as u32 | (byte_at(row, src + 1) as u32) << 8;
+                    let r = (v >> 11) & 0x1F;
+                    let g = (v >> 5) & 0x3F;
+                    let b = v & 0x1F;
+                    ((r << 3 | r >> 2) << 16 | (g << 2 | g >> 4) << 8 | (b << 3 | b >> 2), v != 0)
+                }
+                MODE_RGB888 => {
+                    let v = int_at(row, src) & 0x00FFFFFF;
+                    (v, v != 0)
+                }
+                _ => {
+                    let idx = byte_at(row, src).wrapping_add(cycle) as usize & (palette.len() - 1);
+                    (palette[idx], idx != 0)
+                }
+            };
+            self.window_buffer[dst_line + x] = color_rgba;
+            self.foreground[x] = foreground;
+        }
+    }
+
+    // character matrix rendered from the font in memory
+    fn draw_text_line(&mut self, area: &Area, y: usize, palette: &[u32]) {
+        let cols = area.w / 8;
+        let mut mem = as_mut!(self.mem_ref);
+        let text = mem.read_int_le(VIDEO_TEXT_BASE);
+        let color = mem.read_int_le(VIDEO_TEXT_COLOR);
+        let font = mem.read_int_le(VIDEO_FONT);
+        let row = (y - area.y) / 8;
+        let line = (y - area.y) % 8;
+        let dst_line = y * virpc::SCREEN_WIDTH + area.x;
+
+        let chars = mem.slice(text.wrapping_add((row * cols) as u32), cols);
+        let attrs = mem.slice(color.wrapping_add((row * cols) as u32), cols);
+        let glyphs = mem.slice(font, 256 * 8);
+
+        for col in 0..cols {
+            let charcode = byte_at(chars, col) as usize;
+            let attr = byte_at(attrs, col);
+            let fg = palette[(attr & 0x0F) as usize];
+            let bg = palette[(attr >> 4) as usize];
+            let bits = byte_at(glyphs, charcode * 8 + line);
+            for x in 0..8 {
+                let set = bits & (0x80 >> x) != 0;
+                self.window_buffer[dst_line + col * 8 + x] = if set { fg } else { bg };
+                self.foreground[col * 8 + x] = set;
+            }
+        }
+    }
+
+    // composite the sprites over the line, sprite 0 on top. Collisions are latched in
+    // the collision registers until the program clears them
+    fn draw_sprites_line(&mut self, area: &Area, y: usize, palette: &[u32]) {
+        let mut occupied = vec![0u8; area.w];
+        let mut collision = 0u8;
+        let mut bg_collision = 0u8;
+        let line = (y - area.y) as i32;
+        let dst_line = y * virpc::SCREEN_WIDTH + area.x;
+
+        let mut mem = as_mut!(self.mem_ref);
+        let sprites = mem.slice(SPRITES, 16 * SPRITE_COUNT as usize);
+        for n in (0..SPRITE_COUNT).rev() {
+            let regs = &sprites[16 * n as usize..];
+            let control = regs[SPRITE_CONTROL as usize];
+            if control & 0x01 == 0 {
+                continue;
+            }
+            // the registers are the program's, any value has to work
+            let sprite_y = int_at(regs, SPRITE_Y as usize) as i32;
+            let row = line.wrapping_sub(sprite_y) as u32;
+            if row >= SPRITE_SIZE as u32 {
+                continue;
+            }
+            let pointer = int_at(regs, SPRITE_POINTER as usize);
+            let sprite_x = int_at(regs, SPRITE_X as usize) as i32;
+            let color = palette[regs[SPRITE_COLOR as usize] as usize & (palette.len() - 1)];
+            let behind = control & 0x02 != 0;
+
+            let data = mem.slice(pointer.wrapping_add(2 * row), 2);
+            let bits = ((byte_at(data, 0) as u16) << 8) | byte_at(data, 1) as u16;
+            for col in 0..SPRITE_SIZE {
+                let x = sprite_x.wrapping_add(col as i32);
+                if bits & (0x8000 >> col) == 0 || x < 0 || x >= area.w as i32 {
+                    continue;
+                }
+                let x = x as usize;
+                if occupied[x] != 0 {
+                    collision |= occupied[x] | (1 << n);
+                }
+                occupied[x] |= 1 << n;
+                if self.foreground[x] {
+                    bg_collision |= 1 << n;
+                    if behind {
+                        continue;
+                    }
+                }
+                self.window_buffer[dst_line + x] = color;
+            }
+        }
+
+        if collision == 0 && bg_collision == 0 {
+            return;
+        }
+        let latched = mem.read_byte(SPRITE_COLLISION);
+        let bg_latched = mem.read_byte(SPRITE_BG_COLLISION);
+        let irq = mem.read_byte(SPRITE_IRQ);
+        mem.write_byte(SPRITE_COLLISION, latched | collision);
+        mem.write_byte(SPRITE_BG_COLLISION, bg_latched | bg_collision);
+        drop(mem);
+
+        let new_collision = irq & 0x01 != 0 && collision & !latched != 0;
+        let new_bg_collision = irq & 0x02 != 0 && bg_collision & !bg_latched != 0;
+        if new_collision || new_bg_collision {
+            as_mut!(self.cpu_ref).raise_interrupt(cpu::Interrupt::Sprite);
+        }
+    }
+
     /*
     fn draw_background(&mut self) {
         let dst_color: u8;